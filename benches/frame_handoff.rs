@@ -0,0 +1,157 @@
+// Copyright © SixtyFPS GmbH <info@slint.dev>
+// SPDX-License-Identifier: MIT
+
+//! Measures allocations per frame on the way from the video thread to the renderer, comparing the
+//! pooled handoff of the [`FrameQueue`](frame_queue::FrameQueue) with cloning every frame.
+//!
+//! Run with `cargo bench --bench frame_handoff`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use ffmpeg_next::util::frame::Video;
+
+#[allow(dead_code)]
+#[path = "../src/player/clock.rs"]
+mod clock;
+#[allow(dead_code)]
+#[path = "../src/player/frame_queue.rs"]
+mod frame_queue;
+
+const FRAMES: usize = 1000;
+const WIDTH: u32 = 1920;
+const HEIGHT: u32 = 1080;
+
+/// Counts the heap allocations made through Rust. FFmpeg allocates its frames and buffers with
+/// its own allocator, its buffer allocations are told apart by the identity of the buffers instead.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+struct Measurement {
+    heap_allocations: usize,
+    buffer_allocations: usize,
+    elapsed: std::time::Duration,
+}
+
+impl Measurement {
+    fn print(&self, name: &str) {
+        println!(
+            "{:<8} {:>6.2} heap allocations/frame, {:>6.2} ffmpeg buffer allocations/frame, {:>8.1} µs/frame",
+            name,
+            self.heap_allocations as f64 / FRAMES as f64,
+            self.buffer_allocations as f64 / FRAMES as f64,
+            self.elapsed.as_secs_f64() * 1e6 / FRAMES as f64,
+        );
+    }
+}
+
+/// What the decoder hands out from `receive_frame`: a new reference to its own frame buffers.
+fn receive_frame(decoder_output: &Video, frame: &mut Video) {
+    unsafe {
+        ffmpeg_next::ffi::av_frame_unref(frame.as_mut_ptr());
+        ffmpeg_next::ffi::av_frame_ref(frame.as_mut_ptr(), decoder_output.as_ptr());
+    }
+}
+
+/// The data buffers referenced by a frame, null for unused slots. Kept in an array so that
+/// measuring doesn't allocate.
+fn buffers(frame: &Video) -> [*const ffmpeg_next::ffi::AVBuffer; 8] {
+    unsafe {
+        (*frame.as_ptr()).buf.map(|buffer_ref| {
+            if buffer_ref.is_null() {
+                std::ptr::null()
+            } else {
+                (*buffer_ref).buffer as *const _
+            }
+        })
+    }
+}
+
+/// Number of buffers of the frame the renderer received that were allocated on the way, i.e.
+/// aren't the decoder's own.
+fn allocated_buffers(decoder_output: &Video, frame: &Video) -> usize {
+    let decoder_buffers = buffers(decoder_output);
+    buffers(frame)
+        .iter()
+        .filter(|buffer| !buffer.is_null() && !decoder_buffers.contains(buffer))
+        .count()
+}
+
+fn pooled_handoff(decoder_output: &Video) -> Measurement {
+    let queue = frame_queue::FrameQueue::new(Arc::new(clock::PlaybackClock::new()));
+    let mut decoded_frame = queue.take_frame();
+
+    let heap_allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let mut buffer_allocations = 0;
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        receive_frame(decoder_output, &mut decoded_frame);
+        let presented_frame = std::mem::replace(&mut decoded_frame, queue.take_frame());
+        queue.push(presented_frame, None);
+
+        let frame = queue.pop().unwrap();
+        buffer_allocations += allocated_buffers(decoder_output, &frame);
+    }
+    let elapsed = start.elapsed();
+
+    Measurement {
+        heap_allocations: ALLOCATIONS.load(Ordering::Relaxed) - heap_allocations,
+        buffer_allocations,
+        elapsed,
+    }
+}
+
+fn cloned_handoff(decoder_output: &Video) -> Measurement {
+    let queue = frame_queue::FrameQueue::new(Arc::new(clock::PlaybackClock::new()));
+    let mut decoded_frame = Video::empty();
+
+    let heap_allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let mut buffer_allocations = 0;
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        receive_frame(decoder_output, &mut decoded_frame);
+        queue.push(decoded_frame.clone(), None);
+
+        let frame = queue.pop().unwrap();
+        buffer_allocations += allocated_buffers(decoder_output, &frame);
+    }
+    let elapsed = start.elapsed();
+
+    Measurement {
+        heap_allocations: ALLOCATIONS.load(Ordering::Relaxed) - heap_allocations,
+        buffer_allocations,
+        elapsed,
+    }
+}
+
+fn main() {
+    ffmpeg_next::init().unwrap();
+
+    let decoder_output = Video::new(ffmpeg_next::format::Pixel::YUV420P, WIDTH, HEIGHT);
+
+    println!("{} frames of {}x{} YUV420P", FRAMES, WIDTH, HEIGHT);
+    pooled_handoff(&decoder_output).print("pooled");
+    cloned_handoff(&decoder_output).print("cloned");
+}
@@ -1,107 +1,232 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::mem;
+use std::rc::Rc;
+use anyhow::anyhow;
 use glow::HasContext;
 
-pub struct GLTextureRGB {
-    gl: glow::Context,
+/// A linked vertex and fragment shader. Uniform locations are looked up once and cached.
+pub struct ShaderProgram {
+    gl: Rc<glow::Context>,
     program: glow::Program,
-    texture: glow::Texture,
-    vbo: glow::Buffer,
-    vao: glow::VertexArray,
-    width: u32,
-    height: u32,
+    uniform_locations: RefCell<HashMap<&'static str, Option<glow::UniformLocation>>>,
 }
 
-impl GLTextureRGB {
-    pub fn new(gl: glow::Context, width: u32, height: u32) -> Self {
+impl ShaderProgram {
+    pub fn new(gl: Rc<glow::Context>, vertex_source: &str, fragment_source: &str) -> Result<Self, anyhow::Error> {
         unsafe {
-            // Compile and link shaders
-            let program = gl.create_program().expect("Cannot create program");
-            let vertex_shader = gl.create_shader(glow::VERTEX_SHADER).expect("Cannot create vertex shader");
-            let fragment_shader = gl.create_shader(glow::FRAGMENT_SHADER).expect("Cannot create fragment shader");
-
-            gl.shader_source(vertex_shader, include_str!("shaders/rgb/vertex.glsl"));
-            gl.shader_source(fragment_shader, include_str!("shaders/rgb/fragment.glsl"));
-            gl.compile_shader(vertex_shader);
-            if !gl.get_shader_compile_status(vertex_shader) {
-                panic!("Vertex shader compilation failed: {}", gl.get_shader_info_log(vertex_shader));
+            let program = gl.create_program().map_err(|err| anyhow!("Cannot create program: {}", err))?;
+
+            let mut error = None;
+            let mut shaders = Vec::new();
+            for (shader_type, source, name) in
+                [(glow::VERTEX_SHADER, vertex_source, "Vertex"), (glow::FRAGMENT_SHADER, fragment_source, "Fragment")]
+            {
+                match compile_shader(&gl, shader_type, source) {
+                    Ok(shader) => {
+                        gl.attach_shader(program, shader);
+                        shaders.push(shader);
+                    }
+                    Err(log) => {
+                        error = Some(anyhow!("{} shader compilation failed: {}", name, log));
+                        break;
+                    }
+                }
             }
-            gl.compile_shader(fragment_shader);
-            if !gl.get_shader_compile_status(fragment_shader) {
-                panic!("Fragment shader compilation failed: {}", gl.get_shader_info_log(fragment_shader));
+            if error.is_none() {
+                gl.link_program(program);
+                if !gl.get_program_link_status(program) {
+                    error = Some(anyhow!("Program linking failed: {}", gl.get_program_info_log(program)));
+                }
             }
 
-            gl.attach_shader(program, vertex_shader);
-            gl.attach_shader(program, fragment_shader);
-            gl.link_program(program);
-            if !gl.get_program_link_status(program) {
-                panic!("Program linking failed: {}", gl.get_program_info_log(program));
+            for shader in shaders {
+                gl.detach_shader(program, shader);
+                gl.delete_shader(shader);
+            }
+            if let Some(error) = error {
+                gl.delete_program(program);
+                return Err(error);
             }
 
-            gl.detach_shader(program, vertex_shader);
-            gl.delete_shader(vertex_shader);
-            gl.detach_shader(program, fragment_shader);
-            gl.delete_shader(fragment_shader);
+            Ok(Self { gl, program, uniform_locations: RefCell::new(HashMap::new()) })
+        }
+    }
 
-            // Create and configure the texture
-            let texture = gl.create_texture().expect("Cannot create texture");
-            gl.bind_texture(glow::TEXTURE_2D, Some(texture));
-            gl.tex_image_2d(
-                glow::TEXTURE_2D,
-                0,
-                glow::RGB as i32,
-                width as i32,
-                height as i32,
-                0,
-                glow::RGB,
-                glow::UNSIGNED_BYTE,
-                None,
-            );
-            gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MIN_FILTER, glow::LINEAR as i32);
-            gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MAG_FILTER, glow::LINEAR as i32);
-
-            // Define the vertices of the full-screen quad
-            let vertices: [f32; 24] = [
-                // positions   // texCoords (flipped)
-                -1.0,  1.0,    0.0, 0.0,
-                -1.0, -1.0,    0.0, 1.0,
-                1.0, -1.0,    1.0, 1.0,
-                1.0, -1.0,    1.0, 1.0,
-                1.0,  1.0,    1.0, 0.0,
-                -1.0,  1.0,    0.0, 0.0
-            ];
+    pub fn bind(&self) {
+        unsafe { self.gl.use_program(Some(self.program)) };
+    }
 
+    pub fn attribute_location(&self, name: &str) -> Result<u32, anyhow::Error> {
+        unsafe { self.gl.get_attrib_location(self.program, name) }
+            .ok_or_else(|| anyhow!("Could not find attribute '{}' in shader", name))
+    }
+
+    /// The location of a uniform, `None` if the shader doesn't use it.
+    pub fn uniform_location(&self, name: &'static str) -> Option<glow::UniformLocation> {
+        self.uniform_locations
+            .borrow_mut()
+            .entry(name)
+            .or_insert_with(|| unsafe { self.gl.get_uniform_location(self.program, name) })
+            .clone()
+    }
+
+    /// Sets an integer or sampler uniform of the bound program.
+    pub fn set_uniform_i32(&self, name: &'static str, value: i32) {
+        unsafe { self.gl.uniform_1_i32(self.uniform_location(name).as_ref(), value) };
+    }
+
+    /// Sets a float uniform of the bound program.
+    pub fn set_uniform_f32(&self, name: &'static str, value: f32) {
+        unsafe { self.gl.uniform_1_f32(self.uniform_location(name).as_ref(), value) };
+    }
+}
+
+impl Drop for ShaderProgram {
+    fn drop(&mut self) {
+        unsafe { self.gl.delete_program(self.program) };
+    }
+}
+
+/// Compiles a shader, returning the info log if that fails.
+unsafe fn compile_shader(gl: &glow::Context, shader_type: u32, source: &str) -> Result<glow::Shader, String> {
+    let shader = gl.create_shader(shader_type)?;
+    gl.shader_source(shader, source);
+    gl.compile_shader(shader);
+    if gl.get_shader_compile_status(shader) {
+        Ok(shader)
+    } else {
+        let log = gl.get_shader_info_log(shader);
+        gl.delete_shader(shader);
+        Err(log)
+    }
+}
+
+/// A quad covering the viewport, feeding the `position` and `texCoords` attributes of a shader
+/// program. Texture coordinates are flipped vertically, as frames are stored top row first.
+pub struct FullscreenQuad {
+    gl: Rc<glow::Context>,
+    vbo: glow::Buffer,
+    vao: glow::VertexArray,
+}
+
+impl FullscreenQuad {
+    pub fn new(gl: Rc<glow::Context>, program: &ShaderProgram) -> Result<Self, anyhow::Error> {
+        let position_location = program.attribute_location("position")?;
+        let tex_coords_location = program.attribute_location("texCoords")?;
+
+        // Define the vertices of the full-screen quad
+        let vertices: [f32; 24] = [
+            // positions   // texCoords (flipped)
+            -1.0,  1.0,    0.0, 0.0,
+            -1.0, -1.0,    0.0, 1.0,
+            1.0, -1.0,    1.0, 1.0,
+            1.0, -1.0,    1.0, 1.0,
+            1.0,  1.0,    1.0, 0.0,
+            -1.0,  1.0,    0.0, 0.0
+        ];
+
+        unsafe {
             // Create and bind the VAO
-            let vao = gl.create_vertex_array().expect("Cannot create vertex array");
+            let vao = gl.create_vertex_array().map_err(|err| anyhow!("Cannot create vertex array: {}", err))?;
             gl.bind_vertex_array(Some(vao));
 
             // Create and bind the VBO
-            let vbo = gl.create_buffer().expect("Cannot create buffer");
+            let vbo = match gl.create_buffer() {
+                Ok(vbo) => vbo,
+                Err(err) => {
+                    gl.delete_vertex_array(vao);
+                    return Err(anyhow!("Cannot create buffer: {}", err));
+                }
+            };
             gl.bind_buffer(glow::ARRAY_BUFFER, Some(vbo));
             gl.buffer_data_u8_slice(glow::ARRAY_BUFFER, &vertices.align_to::<u8>().1, glow::STATIC_DRAW);
 
             // Configure the vertex attributes
             let stride = 4 * mem::size_of::<f32>() as i32;
+            gl.vertex_attrib_pointer_f32(position_location, 2, glow::FLOAT, false, stride, 0);
+            gl.enable_vertex_attrib_array(position_location);
+            gl.vertex_attrib_pointer_f32(tex_coords_location, 2, glow::FLOAT, false, stride, 2 * mem::size_of::<f32>() as i32);
+            gl.enable_vertex_attrib_array(tex_coords_location);
 
-            let position_location = gl.get_attrib_location(program, "position")
-                .expect("Could not find attribute 'position' in shader");
-            gl.vertex_attrib_pointer_f32(position_location as u32, 2, glow::FLOAT, false, stride, 0);
-            gl.enable_vertex_attrib_array(position_location as u32);
+            Ok(Self { gl, vbo, vao })
+        }
+    }
 
-            let tex_coords_location = gl.get_attrib_location(program, "texCoords")
-                .expect("Could not find attribute 'texCoords' in shader");
-            gl.vertex_attrib_pointer_f32(tex_coords_location as u32, 2, glow::FLOAT, false, stride, 2 * mem::size_of::<f32>() as i32);
-            gl.enable_vertex_attrib_array(tex_coords_location as u32);
+    /// Draws the quad with the bound program.
+    pub fn draw(&self) {
+        unsafe {
+            self.gl.bind_buffer(glow::ARRAY_BUFFER, Some(self.vbo));
+            self.gl.bind_vertex_array(Some(self.vao));
+            self.gl.draw_arrays(glow::TRIANGLES, 0, 6);
+        }
+    }
+}
 
-            Self {
-                gl,
-                program,
-                texture,
-                vbo,
-                vao,
-                width,
-                height
-            }
+impl Drop for FullscreenQuad {
+    fn drop(&mut self) {
+        unsafe {
+            self.gl.delete_buffer(self.vbo);
+            self.gl.delete_vertex_array(self.vao);
+        }
+    }
+}
+
+/// Creates a texture with linear filtering and no storage yet.
+fn create_texture(gl: &glow::Context) -> Result<glow::Texture, anyhow::Error> {
+    unsafe {
+        let texture = gl.create_texture().map_err(|err| anyhow!("Cannot create texture: {}", err))?;
+        gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+        gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MIN_FILTER, glow::LINEAR as i32);
+        gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MAG_FILTER, glow::LINEAR as i32);
+        Ok(texture)
+    }
+}
+
+pub struct GLTextureRGB {
+    gl: Rc<glow::Context>,
+    program: ShaderProgram,
+    quad: FullscreenQuad,
+    texture: glow::Texture,
+    pixel_buffers: PixelBufferRing,
+    width: u32,
+    height: u32,
+}
+
+impl GLTextureRGB {
+    pub fn new(gl: Rc<glow::Context>, width: u32, height: u32) -> Result<Self, anyhow::Error> {
+        let program = ShaderProgram::new(
+            gl.clone(),
+            include_str!("shaders/rgb/vertex.glsl"),
+            include_str!("shaders/rgb/fragment.glsl"),
+        )?;
+        let quad = FullscreenQuad::new(gl.clone(), &program)?;
+
+        // Create and configure the texture
+        let texture = create_texture(&gl)?;
+        unsafe {
+            gl.tex_image_2d(
+                glow::TEXTURE_2D,
+                0,
+                glow::RGB as i32,
+                width as i32,
+                height as i32,
+                0,
+                glow::RGB,
+                glow::UNSIGNED_BYTE,
+                None,
+            );
         }
+
+        Ok(Self {
+            pixel_buffers: PixelBufferRing::new(gl.clone())?,
+            gl,
+            program,
+            quad,
+            texture,
+            width,
+            height,
+        })
     }
 
     pub fn update_with_frame(&self, frame_data: &[u8]) {
@@ -127,318 +252,888 @@ impl GLTextureRGB {
         }
     }
 
+    /// The GL upload format and bytes per pixel of the packed RGB formats we can show directly.
+    fn upload_format(format: ffmpeg_next::format::Pixel) -> Option<(u32, usize)> {
+        match format {
+            ffmpeg_next::format::Pixel::RGB24 => Some((glow::RGB, 3)),
+            ffmpeg_next::format::Pixel::BGR24 => Some((glow::BGR, 3)),
+            ffmpeg_next::format::Pixel::RGBA | ffmpeg_next::format::Pixel::RGBZ => Some((glow::RGBA, 4)),
+            ffmpeg_next::format::Pixel::BGRA | ffmpeg_next::format::Pixel::BGRZ => Some((glow::BGRA, 4)),
+            _ => None,
+        }
+    }
+
+    pub fn supports(format: ffmpeg_next::format::Pixel) -> bool {
+        Self::upload_format(format).is_some()
+    }
+
+    pub fn read_video_frame(&mut self, video_frame: &ffmpeg_next::frame::Video) {
+        let Some((upload_format, bytes_per_pixel)) = Self::upload_format(video_frame.format()) else {
+            panic!("Unsupported pixel format: {:?}", video_frame.format());
+        };
+        if (self.width, self.height) != (video_frame.width(), video_frame.height()) {
+            self.width = video_frame.width();
+            self.height = video_frame.height();
+            unsafe {
+                self.gl.bind_texture(glow::TEXTURE_2D, Some(self.texture));
+                self.gl.tex_image_2d(
+                    glow::TEXTURE_2D,
+                    0,
+                    glow::RGB as i32,
+                    self.width as i32,
+                    self.height as i32,
+                    0,
+                    glow::RGB,
+                    glow::UNSIGNED_BYTE,
+                    None,
+                );
+            }
+        }
+
+        self.pixel_buffers.upload(&[PlaneUpload {
+            texture: self.texture,
+            format: upload_format,
+            data_type: glow::UNSIGNED_BYTE,
+            width: self.width,
+            height: self.height,
+            bytes_per_pixel,
+            data: video_frame.data(0),
+            stride: video_frame.stride(0),
+        }]);
+    }
+
     pub fn render(&self) {
+        self.program.bind();
         unsafe {
             let gl = &self.gl;
-            gl.use_program(Some(self.program));
-            gl.clear_color(0.0, 0.0, 1.0, 1.0);
+            // Black, for the bars around letterboxed video
+            gl.clear_color(0.0, 0.0, 0.0, 1.0);
             gl.clear(glow::COLOR_BUFFER_BIT);
-            // gl.viewport(0, 0, self.width as i32, self.height as i32);
             // Ensure the texture is bound to the correct texture unit
             gl.active_texture(glow::TEXTURE0);
             gl.bind_texture(glow::TEXTURE_2D, Some(self.texture));
-
-            let texture_location = self.gl.get_uniform_location(self.program, "screenTexture");
-            gl.uniform_1_i32(texture_location.as_ref(), 0); // 0 refers to texture unit 0
-            
-            gl.bind_buffer(glow::ARRAY_BUFFER, Some(self.vbo)); 
-            gl.bind_vertex_array(Some(self.vao));
-            gl.draw_arrays(glow::TRIANGLES, 0, 6);
         }
+        self.program.set_uniform_i32("screenTexture", 0); // 0 refers to texture unit 0
+        self.quad.draw();
     }
 }
 
 impl Drop for GLTextureRGB {
     fn drop(&mut self) {
-        unsafe {
-            self.gl.delete_program(self.program);
-            self.gl.delete_texture(self.texture);
-            self.gl.delete_buffer(self.vbo);
-            self.gl.delete_vertex_array(self.vao);
-        }
+        unsafe { self.gl.delete_texture(self.texture) };
     }
 }
 
 pub struct GLTextureYUV {
-    gl: glow::Context,
-    program: glow::Program,
+    gl: Rc<glow::Context>,
+    program: ShaderProgram,
+    quad: FullscreenQuad,
     texture_y: glow::Texture,
     texture_u: glow::Texture,
     texture_v: glow::Texture,
-    vbo: glow::Buffer,
-    vao: glow::VertexArray,
+    pixel_buffers: PixelBufferRing,
     width: u32,
     height: u32,
+    /// Layout the textures are currently allocated for.
+    layout: Option<YUVLayout>,
+    /// Color matrix and range of the last frame.
+    matrix: ColorMatrix,
+    full_range: bool,
+    /// Transfer function of the last frame, HDR ones are tone mapped to SDR.
+    transfer: Transfer,
+    /// Brightest pixel of the content in nits, from its content light level metadata.
+    peak_luminance: f32,
+    tone_mapping: ToneMapping,
 }
 
 impl GLTextureYUV {
-    pub fn new(gl: glow::Context, width: u32, height: u32) -> Self {
+    pub fn new(gl: Rc<glow::Context>, width: u32, height: u32) -> Result<Self, anyhow::Error> {
+        let program = ShaderProgram::new(
+            gl.clone(),
+            include_str!("shaders/yuv/vertex.glsl"),
+            include_str!("shaders/yuv/fragment.glsl"),
+        )?;
+        let quad = FullscreenQuad::new(gl.clone(), &program)?;
+
+        // Create the textures, their storage is allocated once the pixel format is known
+        let texture_y = create_texture(&gl)?;
+        let texture_u = create_texture(&gl)?;
+        let texture_v = create_texture(&gl)?;
+
+        Ok(Self {
+            pixel_buffers: PixelBufferRing::new(gl.clone())?,
+            gl,
+            program,
+            quad,
+            texture_y,
+            texture_u,
+            texture_v,
+            width,
+            height,
+            layout: None,
+            matrix: ColorMatrix::BT601,
+            full_range: false,
+            transfer: Transfer::Sdr,
+            peak_luminance: DEFAULT_HDR_PEAK_LUMINANCE,
+            tone_mapping: ToneMapping::default(),
+        })
+    }
+
+    pub fn supports(format: ffmpeg_next::format::Pixel) -> bool {
+        YUVLayout::of(format).is_some()
+    }
+
+    /// (Re)allocates the plane textures for `layout`.
+    fn allocate_textures(&mut self, layout: YUVLayout) {
+        let gl = &self.gl;
+        let (chroma_width, chroma_height) = layout.chroma_size(self.width, self.height);
+
+        let mut planes = vec![(self.texture_y, glow::R16, glow::RED, self.width, self.height)];
+        if layout.semi_planar {
+            planes.push((self.texture_u, glow::RG16, glow::RG, chroma_width, chroma_height));
+        } else {
+            planes.push((self.texture_u, glow::R16, glow::RED, chroma_width, chroma_height));
+            planes.push((self.texture_v, glow::R16, glow::RED, chroma_width, chroma_height));
+        }
+
         unsafe {
-            // Compile and link shaders
-            let program = gl.create_program().expect("Cannot create program");
-            let vertex_shader = gl.create_shader(glow::VERTEX_SHADER).expect("Cannot create vertex shader");
-            let fragment_shader = gl.create_shader(glow::FRAGMENT_SHADER).expect("Cannot create fragment shader");
-
-            gl.shader_source(vertex_shader, include_str!("shaders/yuv/vertex.glsl"));
-            gl.shader_source(fragment_shader, include_str!("shaders/yuv/fragment.glsl"));
-            gl.compile_shader(vertex_shader);
-            if !gl.get_shader_compile_status(vertex_shader) {
-                panic!("Vertex shader compilation failed: {}", gl.get_shader_info_log(vertex_shader));
+            for (texture, internal_format, format, width, height) in planes {
+                gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+                gl.tex_image_2d(
+                    glow::TEXTURE_2D,
+                    0,
+                    internal_format as i32,
+                    width as i32,
+                    height as i32,
+                    0,
+                    format,
+                    glow::UNSIGNED_SHORT,
+                    None,
+                );
             }
-            gl.compile_shader(fragment_shader);
-            if !gl.get_shader_compile_status(fragment_shader) {
-                panic!("Fragment shader compilation failed: {}", gl.get_shader_info_log(fragment_shader));
+        }
+        self.layout = Some(layout);
+    }
+
+    pub fn read_video_frame(&mut self, video_frame: &ffmpeg_next::frame::Video) {
+        let format = video_frame.format();
+        let Some(layout) = YUVLayout::of(format) else {
+            panic!("Unsupported pixel format: {:?}", format);
+        };
+        // Streams may change their resolution or format mid-stream (adaptive streams, spliced
+        // clips), the textures have to match every frame.
+        if self.layout != Some(layout) || (self.width, self.height) != (video_frame.width(), video_frame.height()) {
+            self.width = video_frame.width();
+            self.height = video_frame.height();
+            self.allocate_textures(layout);
+        }
+        self.matrix = ColorMatrix::of(video_frame);
+        self.transfer = Transfer::of(video_frame);
+        if let Some(max_cll) = content_light_level(video_frame).filter(|max_cll| *max_cll > 0) {
+            self.peak_luminance = max_cll as f32;
+        }
+        self.full_range = match video_frame.color_range() {
+            ffmpeg_next::color::Range::JPEG => true,
+            ffmpeg_next::color::Range::MPEG => false,
+            // The deprecated YUVJ formats are full range by definition
+            _ => matches!(
+                format,
+                ffmpeg_next::format::Pixel::YUVJ420P
+                    | ffmpeg_next::format::Pixel::YUVJ422P
+                    | ffmpeg_next::format::Pixel::YUVJ444P
+            ),
+        };
+
+        let width = video_frame.width();
+        let height = video_frame.height();
+        let (chroma_width, chroma_height) = layout.chroma_size(width, height);
+        let bytes_per_sample = layout.bytes_per_sample();
+        let data_type = if bytes_per_sample == 2 { glow::UNSIGNED_SHORT } else { glow::UNSIGNED_BYTE };
+
+        let mut planes = vec![(self.texture_y, glow::RED, 1, width, height)];
+        if layout.semi_planar {
+            planes.push((self.texture_u, glow::RG, 2, chroma_width, chroma_height));
+        } else {
+            planes.push((self.texture_u, glow::RED, 1, chroma_width, chroma_height));
+            planes.push((self.texture_v, glow::RED, 1, chroma_width, chroma_height));
+        }
+
+        let planes: Vec<_> = planes
+            .into_iter()
+            .enumerate()
+            .map(|(index, (texture, format, components, width, height))| PlaneUpload {
+                texture,
+                format,
+                data_type,
+                width,
+                height,
+                bytes_per_pixel: components * bytes_per_sample,
+                data: video_frame.data(index),
+                stride: video_frame.stride(index),
+            })
+            .collect();
+        self.pixel_buffers.upload(&planes);
+    }
+
+    /// Whether the last frame uses an HDR transfer function and is tone mapped.
+    pub fn is_hdr(&self) -> bool {
+        self.transfer != Transfer::Sdr
+    }
+
+    pub fn set_tone_mapping(&mut self, tone_mapping: ToneMapping) {
+        self.tone_mapping = tone_mapping;
+    }
+
+    pub fn render(&self) {
+        self.program.bind();
+        unsafe {
+            let gl = &self.gl;
+            // Black, for the bars around letterboxed video
+            gl.clear_color(0.0, 0.0, 0.0, 1.0);
+            gl.clear(glow::COLOR_BUFFER_BIT);
+            // Ensure the textures are bound to the correct texture units
+            gl.active_texture(glow::TEXTURE0);
+            gl.bind_texture(glow::TEXTURE_2D, Some(self.texture_y));
+
+            gl.active_texture(glow::TEXTURE1);
+            gl.bind_texture(glow::TEXTURE_2D, Some(self.texture_u));
+
+            gl.active_texture(glow::TEXTURE2);
+            gl.bind_texture(glow::TEXTURE_2D, Some(self.texture_v));
+        }
+
+        let program = &self.program;
+        program.set_uniform_i32("yTexture", 0); // 0 refers to texture unit 0
+        program.set_uniform_i32("uTexture", 1); // 1 refers to texture unit 1
+        program.set_uniform_i32("vTexture", 2); // 2 refers to texture unit 2
+
+        // NV12 and P010 carry U and V interleaved in the uTexture
+        program.set_uniform_i32("semiPlanar", self.layout.is_some_and(|layout| layout.semi_planar) as i32);
+        program.set_uniform_i32("colorMatrix", self.matrix as i32);
+        program.set_uniform_i32("fullRange", self.full_range as i32);
+        program.set_uniform_i32("bitDepth", self.layout.map_or(8, |layout| layout.bit_depth) as i32);
+        program.set_uniform_i32("transfer", self.transfer as i32);
+        program.set_uniform_i32("toneMapping", self.tone_mapping as i32);
+        program.set_uniform_f32("peakLuminance", self.peak_luminance);
+
+        self.quad.draw();
+    }
+}
+
+impl Drop for GLTextureYUV {
+    fn drop(&mut self) {
+        unsafe {
+            self.gl.delete_texture(self.texture_y);
+            self.gl.delete_texture(self.texture_u);
+            self.gl.delete_texture(self.texture_v);
+        }
+    }
+}
+
+/// Number of pixel buffers uploads rotate through. The driver may still be copying the previous
+/// frames from their buffers into the textures while the next one is filled.
+const PIXEL_BUFFER_COUNT: usize = 3;
+
+/// Planes are placed in a pixel buffer at offsets aligned to this many bytes.
+const PIXEL_BUFFER_PLANE_ALIGNMENT: usize = 64;
+
+/// One plane of a frame to upload into a texture.
+struct PlaneUpload<'a> {
+    texture: glow::Texture,
+    /// GL format and data type of the pixels.
+    format: u32,
+    data_type: u32,
+    width: u32,
+    height: u32,
+    bytes_per_pixel: usize,
+    data: &'a [u8],
+    /// Bytes from one row to the next, including padding.
+    stride: usize,
+}
+
+impl PlaneUpload<'_> {
+    /// The `UNPACK_ROW_LENGTH` and size in bytes of the plane once staged. Padded rows are kept
+    /// and skipped through the row length, unless the stride isn't a whole number of pixels (as
+    /// with RGB24), in which case the rows are packed tightly and the row length is 0.
+    fn staged_layout(&self) -> (usize, usize) {
+        let row_bytes = self.width as usize * self.bytes_per_pixel;
+        let rows = self.height as usize;
+        if rows == 0 {
+            (0, 0)
+        } else if self.stride % self.bytes_per_pixel == 0 {
+            (self.stride / self.bytes_per_pixel, self.stride * (rows - 1) + row_bytes)
+        } else {
+            (0, row_bytes * rows)
+        }
+    }
+
+    /// Copies the plane into `staging`, laid out as described by [`Self::staged_layout`].
+    fn stage(&self, row_length: usize, staging: &mut [u8]) {
+        if row_length > 0 {
+            staging.copy_from_slice(&self.data[..staging.len()]);
+        } else {
+            let row_bytes = self.width as usize * self.bytes_per_pixel;
+            for (row, staged_row) in staging.chunks_exact_mut(row_bytes).enumerate() {
+                staged_row.copy_from_slice(&self.data[row * self.stride..][..row_bytes]);
             }
+        }
+    }
+}
 
-            gl.attach_shader(program, vertex_shader);
-            gl.attach_shader(program, fragment_shader);
-            gl.link_program(program);
-            if !gl.get_program_link_status(program) {
-                panic!("Program linking failed: {}", gl.get_program_info_log(program));
+/// Uploads frames through a ring of pixel buffer objects. Filling a mapped buffer doesn't wait
+/// for the GPU and the copy from the buffer into the textures happens asynchronously, instead of
+/// stalling the render thread on large frames.
+struct PixelBufferRing {
+    gl: Rc<glow::Context>,
+    buffers: [glow::Buffer; PIXEL_BUFFER_COUNT],
+    /// Size in bytes of the storage allocated for each buffer.
+    sizes: [usize; PIXEL_BUFFER_COUNT],
+    next: usize,
+    /// Uploads are staged here instead if a buffer can't be mapped.
+    fallback: Vec<u8>,
+    /// Whether falling back was reported already, so that it isn't repeated for every frame.
+    fallback_reported: bool,
+}
+
+impl PixelBufferRing {
+    fn new(gl: Rc<glow::Context>) -> Result<Self, anyhow::Error> {
+        let mut buffers = Vec::with_capacity(PIXEL_BUFFER_COUNT);
+        for _ in 0..PIXEL_BUFFER_COUNT {
+            match unsafe { gl.create_buffer() } {
+                Ok(buffer) => buffers.push(buffer),
+                Err(err) => {
+                    buffers.into_iter().for_each(|buffer| unsafe { gl.delete_buffer(buffer) });
+                    return Err(anyhow!("Cannot create pixel buffer: {}", err));
+                }
             }
+        }
+        let buffers = buffers.try_into().unwrap();
+        Ok(Self {
+            gl,
+            buffers,
+            sizes: [0; PIXEL_BUFFER_COUNT],
+            next: 0,
+            fallback: Vec::new(),
+            fallback_reported: false,
+        })
+    }
 
-            gl.detach_shader(program, vertex_shader);
-            gl.delete_shader(vertex_shader);
-            gl.detach_shader(program, fragment_shader);
-            gl.delete_shader(fragment_shader);
+    /// Uploads `planes` into their textures through the next buffer of the ring.
+    fn upload(&mut self, planes: &[PlaneUpload]) {
+        let mut size = 0;
+        let staged_planes: Vec<_> = planes
+            .iter()
+            .map(|plane| {
+                let (row_length, plane_size) = plane.staged_layout();
+                let offset = size;
+                size = (offset + plane_size).next_multiple_of(PIXEL_BUFFER_PLANE_ALIGNMENT);
+                (offset, row_length, plane_size)
+            })
+            .collect();
+        if size == 0 {
+            return;
+        }
 
-            // Create and configure the textures
-            let texture_y = gl.create_texture().expect("Cannot create texture");
-            gl.bind_texture(glow::TEXTURE_2D, Some(texture_y));
-            gl.tex_image_2d(
-                glow::TEXTURE_2D,
-                0,
-                glow::R16 as i32,
-                width as i32,
-                height as i32,
-                0,
-                glow::RED,
-                glow::UNSIGNED_SHORT,
-                None,
-            );
-            gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MIN_FILTER, glow::LINEAR as i32);
-            gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MAG_FILTER, glow::LINEAR as i32);
+        let gl = &self.gl;
+        let index = self.next;
+        self.next = (self.next + 1) % PIXEL_BUFFER_COUNT;
 
-            let texture_u = gl.create_texture().expect("Cannot create texture");
-            gl.bind_texture(glow::TEXTURE_2D, Some(texture_u));
-            gl.tex_image_2d(
-                glow::TEXTURE_2D,
-                0,
-                glow::R16 as i32,
-                width as i32 / 2,
-                height as i32 / 2,
-                0,
-                glow::RED,
-                glow::UNSIGNED_SHORT,
-                None,
-            );
-            gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MIN_FILTER, glow::LINEAR as i32);
-            gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MAG_FILTER, glow::LINEAR as i32);
+        unsafe {
+            gl.bind_buffer(glow::PIXEL_UNPACK_BUFFER, Some(self.buffers[index]));
+            if self.sizes[index] != size {
+                gl.buffer_data_size(glow::PIXEL_UNPACK_BUFFER, size as i32, glow::STREAM_DRAW);
+                self.sizes[index] = size;
+            }
 
-            let texture_v = gl.create_texture().expect("Cannot create texture");
-            gl.bind_texture(glow::TEXTURE_2D, Some(texture_v));
-            gl.tex_image_2d(
-                glow::TEXTURE_2D,
-                0,
-                glow::R16 as i32,
-                width as i32 / 2,
-                height as i32 / 2,
+            // Invalidating lets the driver hand out fresh memory while the GPU still reads the
+            // previous contents, instead of synchronizing.
+            let mapping = gl.map_buffer_range(
+                glow::PIXEL_UNPACK_BUFFER,
                 0,
-                glow::RED,
-                glow::UNSIGNED_SHORT,
-                None,
+                size as i32,
+                glow::MAP_WRITE_BIT | glow::MAP_INVALIDATE_BUFFER_BIT,
             );
-            gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MIN_FILTER, glow::LINEAR as i32);
-            gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MAG_FILTER, glow::LINEAR as i32);
-
-            // Define the vertices of the full-screen quad
-            let vertices: [f32; 24] = [
-                // positions   // texCoords (flipped)
-                -1.0,  1.0,    0.0, 0.0,
-                -1.0, -1.0,    0.0, 1.0,
-                1.0, -1.0,    1.0, 1.0,
-                1.0, -1.0,    1.0, 1.0,
-                1.0,  1.0,    1.0, 0.0,
-                -1.0,  1.0,    0.0, 0.0
-            ];
-
-            // Create and bind the VAO
-            let vao = gl.create_vertex_array().expect("Cannot create vertex array");
-            gl.bind_vertex_array(Some(vao));
+            let staging = if mapping.is_null() {
+                if !self.fallback_reported {
+                    eprintln!("Cannot map pixel buffer, uploading from memory");
+                    self.fallback_reported = true;
+                }
+                gl.bind_buffer(glow::PIXEL_UNPACK_BUFFER, None);
+                self.fallback.resize(size, 0);
+                &mut self.fallback[..]
+            } else {
+                std::slice::from_raw_parts_mut(mapping, size)
+            };
 
-            // Create and bind the VBO
-            let vbo = gl.create_buffer().expect("Cannot create buffer");
-            gl.bind_buffer(glow::ARRAY_BUFFER, Some(vbo));
-            gl.buffer_data_u8_slice(glow::ARRAY_BUFFER, &vertices.align_to::<u8>().1, glow::STATIC_DRAW);
+            for (plane, &(offset, row_length, plane_size)) in planes.iter().zip(&staged_planes) {
+                plane.stage(row_length, &mut staging[offset..][..plane_size]);
+            }
+            if !mapping.is_null() {
+                gl.unmap_buffer(glow::PIXEL_UNPACK_BUFFER);
+            }
 
-            // Configure the vertex attributes
-            let stride = 4 * mem::size_of::<f32>() as i32;
+            // Rows of RGB24 and odd sized chroma planes aren't necessarily 4 byte aligned
+            gl.pixel_store_i32(glow::UNPACK_ALIGNMENT, 1);
+            for (plane, &(offset, row_length, plane_size)) in planes.iter().zip(&staged_planes) {
+                let pixels = if mapping.is_null() {
+                    glow::PixelUnpackData::Slice(&self.fallback[offset..][..plane_size])
+                } else {
+                    glow::PixelUnpackData::BufferOffset(offset as u32)
+                };
+                gl.pixel_store_i32(glow::UNPACK_ROW_LENGTH, row_length as i32);
+                gl.bind_texture(glow::TEXTURE_2D, Some(plane.texture));
+                gl.tex_sub_image_2d(
+                    glow::TEXTURE_2D,
+                    0,
+                    0,
+                    0,
+                    plane.width as i32,
+                    plane.height as i32,
+                    plane.format,
+                    plane.data_type,
+                    pixels,
+                );
+            }
+            gl.pixel_store_i32(glow::UNPACK_ROW_LENGTH, 0);
+            gl.bind_buffer(glow::PIXEL_UNPACK_BUFFER, None);
+        }
+    }
+}
 
-            let position_location = gl.get_attrib_location(program, "position")
-                .expect("Could not find attribute 'position' in shader");
-            gl.vertex_attrib_pointer_f32(position_location as u32, 2, glow::FLOAT, false, stride, 0);
-            gl.enable_vertex_attrib_array(position_location as u32);
-
-            let tex_coords_location = gl.get_attrib_location(program, "texCoords")
-                .expect("Could not find attribute 'texCoords' in shader");
-            gl.vertex_attrib_pointer_f32(tex_coords_location as u32, 2, glow::FLOAT, false, stride, 2 * mem::size_of::<f32>() as i32);
-            gl.enable_vertex_attrib_array(tex_coords_location as u32);
-
-            Self {
-                gl,
-                program,
-                texture_y,
-                texture_u,
-                texture_v,
-                vbo,
-                vao,
-                width,
-                height
+impl Drop for PixelBufferRing {
+    fn drop(&mut self) {
+        unsafe {
+            for buffer in self.buffers {
+                self.gl.delete_buffer(buffer);
             }
         }
+    }
+}
 
+/// How the planes of a YUV pixel format are laid out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct YUVLayout {
+    /// log2 of the horizontal and vertical chroma subsampling, (1, 1) for 4:2:0.
+    chroma_shift: (u32, u32),
+    /// Significant bits per sample. Formats with more than 8 bits store their samples in 16-bit
+    /// words, LSB aligned except for P010 and P016, which therefore count as 16-bit.
+    bit_depth: u32,
+    /// U and V are interleaved in a single plane (NV12, P010).
+    semi_planar: bool,
+}
+
+impl YUVLayout {
+    fn of(format: ffmpeg_next::format::Pixel) -> Option<Self> {
+        use ffmpeg_next::format::Pixel;
+
+        let (chroma_shift, bit_depth, semi_planar) = match format {
+            Pixel::YUV420P | Pixel::YUVJ420P => ((1, 1), 8, false),
+            Pixel::YUV422P | Pixel::YUVJ422P => ((1, 0), 8, false),
+            Pixel::YUV444P | Pixel::YUVJ444P => ((0, 0), 8, false),
+            Pixel::YUV420P10LE => ((1, 1), 10, false),
+            Pixel::YUV422P10LE => ((1, 0), 10, false),
+            Pixel::YUV444P10LE => ((0, 0), 10, false),
+            Pixel::YUV420P12LE => ((1, 1), 12, false),
+            Pixel::YUV422P12LE => ((1, 0), 12, false),
+            Pixel::YUV444P12LE => ((0, 0), 12, false),
+            Pixel::NV12 => ((1, 1), 8, true),
+            Pixel::P010LE | Pixel::P016LE => ((1, 1), 16, true),
+            _ => return None,
+        };
+        Some(Self { chroma_shift, bit_depth, semi_planar })
     }
 
-    pub fn read_video_frame(&self, video_frame: &ffmpeg_next::frame::Video) {
-        let gl = &self.gl;
-    
-        let width = video_frame.width() as usize;
-        let height = video_frame.height() as usize;
+    fn bytes_per_sample(&self) -> usize {
+        if self.bit_depth > 8 { 2 } else { 1 }
+    }
+
+    /// Size of the chroma planes of a `width`x`height` frame, rounded up for odd sizes.
+    fn chroma_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let (shift_x, shift_y) = self.chroma_shift;
+        ((width + (1 << shift_x) - 1) >> shift_x, (height + (1 << shift_y) - 1) >> shift_y)
+    }
+}
+
+/// The YUV to RGB matrices the YUV shader knows, numbered like its `colorMatrix` uniform.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ColorMatrix {
+    BT601 = 0,
+    BT709 = 1,
+    BT2020 = 2,
+}
+
+impl ColorMatrix {
+    fn of(video_frame: &ffmpeg_next::frame::Video) -> Self {
+        use ffmpeg_next::color::Space;
+
+        match video_frame.color_space() {
+            Space::BT709 => ColorMatrix::BT709,
+            Space::BT2020NCL | Space::BT2020CL => ColorMatrix::BT2020,
+            Space::BT470BG | Space::SMPTE170M | Space::FCC => ColorMatrix::BT601,
+            // Untagged streams are BT.709 if they are HD, like most players assume
+            _ if video_frame.height() >= 720 => ColorMatrix::BT709,
+            _ => ColorMatrix::BT601,
+        }
+    }
+
+    /// The color space a frame converted with this matrix is tagged with.
+    fn color_space(self) -> ffmpeg_next::color::Space {
+        match self {
+            ColorMatrix::BT601 => ffmpeg_next::color::Space::SMPTE170M,
+            ColorMatrix::BT709 => ffmpeg_next::color::Space::BT709,
+            ColorMatrix::BT2020 => ffmpeg_next::color::Space::BT2020NCL,
+        }
+    }
+
+    /// The `SWS_CS_*` constant for the matrix.
+    fn swscale_colorspace(self) -> i32 {
+        (match self {
+            ColorMatrix::BT601 => ffmpeg_next::ffi::SWS_CS_ITU601,
+            ColorMatrix::BT709 => ffmpeg_next::ffi::SWS_CS_ITU709,
+            ColorMatrix::BT2020 => ffmpeg_next::ffi::SWS_CS_BT2020,
+        }) as i32
+    }
+}
+
+/// The bit depth of the first component of `format`, and whether it stores RGB or palette
+/// indices rather than YUV.
+fn pixel_format_traits(format: ffmpeg_next::format::Pixel) -> (i32, bool) {
+    let descriptor = unsafe { ffmpeg_next::ffi::av_pix_fmt_desc_get(format.into()) };
+    if descriptor.is_null() {
+        return (8, false);
+    }
+    let descriptor = unsafe { &*descriptor };
+    let rgb_flags = (ffmpeg_next::ffi::AV_PIX_FMT_FLAG_RGB | ffmpeg_next::ffi::AV_PIX_FMT_FLAG_PAL) as u64;
+    (descriptor.comp[0].depth, descriptor.flags & rgb_flags != 0)
+}
+
+/// Peak luminance (in nits) assumed for HDR content without content light level metadata.
+const DEFAULT_HDR_PEAK_LUMINANCE: f32 = 1000.;
+
+/// The transfer functions the YUV shader knows, numbered like its `transfer` uniform.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Transfer {
+    Sdr = 0,
+    /// SMPTE ST 2084, used by HDR10.
+    Pq = 1,
+    /// ARIB STD-B67 hybrid log-gamma.
+    Hlg = 2,
+}
+
+impl Transfer {
+    fn of(video_frame: &ffmpeg_next::frame::Video) -> Self {
+        match video_frame.color_transfer_characteristic() {
+            ffmpeg_next::color::TransferCharacteristic::SMPTE2084 => Transfer::Pq,
+            ffmpeg_next::color::TransferCharacteristic::ARIB_STD_B67 => Transfer::Hlg,
+            _ => Transfer::Sdr,
+        }
+    }
+}
+
+/// The MaxCLL (maximum content light level, in nits) of HDR10 frames that carry it.
+fn content_light_level(video_frame: &ffmpeg_next::frame::Video) -> Option<u32> {
+    let side_data = video_frame.side_data(ffmpeg_next::frame::side_data::Type::ContentLightLevel)?;
+    // AVContentLightMetadata starts with the unsigned MaxCLL
+    let max_cll = side_data.data().get(..4)?;
+    Some(u32::from_ne_bytes(max_cll.try_into().ok()?))
+}
+
+/// How HDR frames are compressed into the SDR range, numbered like the `toneMapping` uniform of
+/// the YUV shader.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ToneMapping {
+    /// The filmic curve from Uncharted 2, with a soft shoulder.
+    Hable = 0,
+    /// Extended Reinhard, keeps the midtones and rolls off towards the peak.
+    Reinhard = 1,
+    /// The EETF of ITU-R BT.2390, applied in the PQ domain.
+    #[default]
+    BT2390 = 2,
+}
+
+/// Renders decoded frames of any pixel format: YUV formats through [`GLTextureYUV`], packed RGB
+/// through [`GLTextureRGB`], and everything else after converting it to YUV420P (or YUV420P10LE
+/// for high bit depths) with swscale.
+pub struct GLVideoRenderer {
+    gl: Rc<glow::Context>,
+    yuv: GLTextureYUV,
+    rgb: GLTextureRGB,
+    showing_rgb: bool,
+    converter: Option<ffmpeg_next::software::scaling::Context>,
+    converted_frame: ffmpeg_next::frame::Video,
+    /// Size and aspect ratio of the last frame.
+    geometry: VideoGeometry,
+    /// The sample aspect ratio the container sets for the stream, if any.
+    stream_sample_aspect: Option<ffmpeg_next::Rational>,
+    scale_mode: ScaleMode,
+}
+
+/// Size and pixel format of the video, as reported to the UI.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VideoGeometry {
+    pub width: u32,
+    pub height: u32,
+    /// Width / height as displayed, taking the sample aspect ratio into account.
+    pub display_aspect: f64,
+    pub format: ffmpeg_next::format::Pixel,
+}
+
+impl GLVideoRenderer {
+    pub fn new(gl: glow::Context, width: u32, height: u32) -> Result<Self, anyhow::Error> {
+        let gl = Rc::new(gl);
+        Ok(Self {
+            yuv: GLTextureYUV::new(gl.clone(), width, height)?,
+            rgb: GLTextureRGB::new(gl.clone(), width, height)?,
+            gl,
+            showing_rgb: false,
+            converter: None,
+            converted_frame: ffmpeg_next::frame::Video::empty(),
+            stream_sample_aspect: None,
+            geometry: VideoGeometry {
+                width,
+                height,
+                display_aspect: width as f64 / height as f64,
+                format: ffmpeg_next::format::Pixel::None,
+            },
+            scale_mode: ScaleMode::default(),
+        })
+    }
+
+    /// Uses the sample aspect ratio of the stream instead of that of the frames, e.g. the one the
+    /// display size of a Matroska track implies, like `av_guess_sample_aspect_ratio` does.
+    pub fn set_stream_sample_aspect_ratio(&mut self, sample_aspect: Option<ffmpeg_next::Rational>) {
+        self.stream_sample_aspect = sample_aspect;
+    }
+
+    /// Uploads a frame, returning the new geometry if it differs from that of the previous
+    /// frame.
+    pub fn read_video_frame(&mut self, video_frame: &ffmpeg_next::frame::Video) -> Option<VideoGeometry> {
+        let geometry = VideoGeometry {
+            width: video_frame.width(),
+            height: video_frame.height(),
+            display_aspect: display_aspect(video_frame, self.stream_sample_aspect),
+            format: video_frame.format(),
+        };
+        let geometry_changed = geometry != self.geometry;
+        self.geometry = geometry;
+
+        self.upload_video_frame(video_frame);
+        geometry_changed.then_some(geometry)
+    }
+
+    fn upload_video_frame(&mut self, video_frame: &ffmpeg_next::frame::Video) {
         let format = video_frame.format();
-        
-        let stride_y = video_frame.stride(0);
-        let stride_u = video_frame.stride(1);
-        let stride_v = video_frame.stride(2);
+        if GLTextureYUV::supports(format) {
+            self.yuv.read_video_frame(video_frame);
+            self.showing_rgb = false;
+        } else if GLTextureRGB::supports(format) {
+            self.rgb.read_video_frame(video_frame);
+            self.showing_rgb = true;
+        } else {
+            let converter_matches = self.converter.as_ref().is_some_and(|converter| {
+                let input = converter.input();
+                input.format == format
+                    && input.width == video_frame.width()
+                    && input.height == video_frame.height()
+            });
+            let (depth, is_rgb) = pixel_format_traits(format);
+            if !converter_matches {
+                self.converted_frame = ffmpeg_next::frame::Video::empty();
+                self.converter = ffmpeg_next::software::scaling::Context::get(
+                    format,
+                    video_frame.width(),
+                    video_frame.height(),
+                    if depth > 8 {
+                        ffmpeg_next::format::Pixel::YUV420P10LE
+                    } else {
+                        ffmpeg_next::format::Pixel::YUV420P
+                    },
+                    video_frame.width(),
+                    video_frame.height(),
+                    ffmpeg_next::software::scaling::Flags::BILINEAR,
+                )
+                .map_err(|err| eprintln!("Cannot convert pixel format {:?}: {}", format, err))
+                .ok();
+            }
 
-        let y_data = video_frame.data(0);
-        let u_data = video_frame.data(1);
-        let v_data = video_frame.data(2);
+            // Convert to the matrix and range the converted frame is tagged with: the source's own
+            // for YUV, BT.709 (BT.2020 for wide gamut) in limited range for RGB and palettes.
+            let (matrix, full_range) = if is_rgb {
+                let wide_gamut = video_frame.color_primaries() == ffmpeg_next::color::Primaries::BT2020;
+                (if wide_gamut { ColorMatrix::BT2020 } else { ColorMatrix::BT709 }, false)
+            } else {
+                (ColorMatrix::of(video_frame), video_frame.color_range() == ffmpeg_next::color::Range::JPEG)
+            };
 
-        unsafe {
-            match format {
-                ffmpeg_next::format::Pixel::YUV420P => {
-                    // Update the Y texture
-                    gl.bind_texture(glow::TEXTURE_2D, Some(self.texture_y));
-                    gl.tex_sub_image_2d(
-                        glow::TEXTURE_2D,
-                        0,
-                        0,
-                        0,
-                        width as i32,
-                        height as i32,
-                        glow::RED,
-                        glow::UNSIGNED_BYTE,
-                        glow::PixelUnpackData::Slice(&y_data[..(stride_y * height)]),
-                    );
-    
-                    // Update the U texture
-                    gl.bind_texture(glow::TEXTURE_2D, Some(self.texture_u));
-                    gl.tex_sub_image_2d(
-                        glow::TEXTURE_2D,
-                        0,
-                        0,
-                        0,
-                        (width / 2) as i32,
-                        (height / 2) as i32,
-                        glow::RED,
-                        glow::UNSIGNED_BYTE,
-                        glow::PixelUnpackData::Slice(&u_data[..(stride_u * (height / 2))]),
-                    );
-    
-                    // Update the V texture
-                    gl.bind_texture(glow::TEXTURE_2D, Some(self.texture_v));
-                    gl.tex_sub_image_2d(
-                        glow::TEXTURE_2D,
-                        0,
-                        0,
-                        0,
-                        (width / 2) as i32,
-                        (height / 2) as i32,
-                        glow::RED,
-                        glow::UNSIGNED_BYTE,
-                        glow::PixelUnpackData::Slice(&v_data[..(stride_v * (height / 2))]),
-                    );
-                },
-                ffmpeg_next::format::Pixel::YUV420P10LE => {
-                    // Update the Y texture
-                    gl.bind_texture(glow::TEXTURE_2D, Some(self.texture_y));
-                    gl.tex_sub_image_2d(
-                        glow::TEXTURE_2D,
-                        0,
-                        0,
-                        0,
-                        width as i32,
-                        height as i32,
-                        glow::RED,
-                        glow::UNSIGNED_SHORT,
-                        glow::PixelUnpackData::Slice(&y_data[..(stride_y * height)]),
-                    );
-            
-                    // Update the U texture
-                    gl.bind_texture(glow::TEXTURE_2D, Some(self.texture_u));
-                    gl.tex_sub_image_2d(
-                        glow::TEXTURE_2D,
-                        0,
-                        0,
-                        0,
-                        (width / 2) as i32,
-                        (height / 2) as i32,
-                        glow::RED,
-                        glow::UNSIGNED_SHORT,
-                        glow::PixelUnpackData::Slice(&u_data[..(stride_u * (height / 2))]),
-                    );
-            
-                    // Update the V texture
-                    gl.bind_texture(glow::TEXTURE_2D, Some(self.texture_v));
-                    gl.tex_sub_image_2d(
-                        glow::TEXTURE_2D,
-                        0,
-                        0,
-                        0,
-                        (width / 2) as i32,
-                        (height / 2) as i32,
-                        glow::RED,
-                        glow::UNSIGNED_SHORT,
-                        glow::PixelUnpackData::Slice(&v_data[..(stride_v * (height / 2))]),
-                    );
-                },
-                _ => {
-                    panic!("Unsupported pixel format: {:?}", format);
+            let Some(converter) = self.converter.as_mut() else { return };
+            unsafe {
+                let coefficients = ffmpeg_next::ffi::sws_getCoefficients(matrix.swscale_colorspace());
+                ffmpeg_next::ffi::sws_setColorspaceDetails(
+                    converter.as_mut_ptr(),
+                    coefficients,
+                    if is_rgb { 1 } else { full_range as i32 },
+                    coefficients,
+                    full_range as i32,
+                    0,
+                    1 << 16,
+                    1 << 16,
+                );
+            }
+            if let Err(err) = converter.run(video_frame, &mut self.converted_frame) {
+                eprintln!("Error converting pixel format {:?}: {}", format, err);
+                return;
+            }
+
+            // Keep the transfer, primaries and HDR metadata of the source, so that it is still
+            // tone mapped
+            unsafe {
+                let converted_frame = self.converted_frame.as_mut_ptr();
+                // Copying adds to the side data instead of replacing that of the previous frame
+                while (*converted_frame).nb_side_data > 0 {
+                    let side_data_type = (**(*converted_frame).side_data).type_;
+                    ffmpeg_next::ffi::av_frame_remove_side_data(converted_frame, side_data_type);
                 }
+                ffmpeg_next::ffi::av_frame_copy_props(converted_frame, video_frame.as_ptr());
             }
+            self.converted_frame.set_color_space(matrix.color_space());
+            self.converted_frame.set_color_range(if full_range {
+                ffmpeg_next::color::Range::JPEG
+            } else {
+                ffmpeg_next::color::Range::MPEG
+            });
+            self.yuv.read_video_frame(&self.converted_frame);
+            self.showing_rgb = false;
         }
     }
-    
-    pub fn render(&self) {
+
+    pub fn is_hdr(&self) -> bool {
+        !self.showing_rgb && self.yuv.is_hdr()
+    }
+
+    pub fn set_tone_mapping(&mut self, tone_mapping: ToneMapping) {
+        self.yuv.set_tone_mapping(tone_mapping);
+    }
+
+    pub fn set_scale_mode(&mut self, scale_mode: ScaleMode) {
+        self.scale_mode = scale_mode;
+    }
+
+    /// Draws the last frame into a `window_width`x`window_height` window, letterboxed or cropped
+    /// according to the scale mode.
+    pub fn render(&self, window_width: u32, window_height: u32) {
+        let (x, y, width, height) =
+            video_viewport(window_width, window_height, self.geometry.display_aspect, self.scale_mode);
+
         unsafe {
             let gl = &self.gl;
-            gl.use_program(Some(self.program));
-            gl.clear_color(0.0, 0.0, 1.0, 1.0);
-            gl.clear(glow::COLOR_BUFFER_BIT);
-            // gl.viewport(0, 0, self.width as i32, self.height as i32);
-            // Ensure the texture is bound to the correct texture unit
-            gl.active_texture(glow::TEXTURE0);
-            gl.bind_texture(glow::TEXTURE_2D, Some(self.texture_y));
-
-            gl.active_texture(glow::TEXTURE1);
-            gl.bind_texture(glow::TEXTURE_2D, Some(self.texture_u));
+            let mut previous_viewport = [0; 4];
+            gl.get_parameter_i32_slice(glow::VIEWPORT, &mut previous_viewport);
+            gl.viewport(x, y, width, height);
 
-            gl.active_texture(glow::TEXTURE2);
-            gl.bind_texture(glow::TEXTURE_2D, Some(self.texture_v));
+            if self.showing_rgb {
+                self.rgb.render();
+            } else {
+                self.yuv.render();
+            }
 
-            let texture_y_location = self.gl.get_uniform_location(self.program, "yTexture");
-            gl.uniform_1_i32(texture_y_location.as_ref(), 0); // 0 refers to texture unit 0
+            let [x, y, width, height] = previous_viewport;
+            gl.viewport(x, y, width, height);
+        }
+    }
+}
 
-            let texture_u_location = self.gl.get_uniform_location(self.program, "uTexture");
-            gl.uniform_1_i32(texture_u_location.as_ref(), 1); // 1 refers to texture unit 1
+/// How the video is fitted into a window of a different shape.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScaleMode {
+    /// Show the whole video, with black bars on two sides.
+    #[default]
+    Fit,
+    /// Cover the whole window, cropping two sides of the video.
+    Fill,
+    /// Cover the whole window, ignoring the aspect ratio.
+    Stretch,
+    /// Halfway between fit and fill, cropping half of what fill would.
+    Zoom,
+}
 
-            let texture_v_location = self.gl.get_uniform_location(self.program, "vTexture");
-            gl.uniform_1_i32(texture_v_location.as_ref(), 2); // 2 refers to texture unit 2
+/// Width / height of a frame as displayed, using the sample aspect ratio of the stream, or else
+/// that of the frame, for anamorphic video.
+fn display_aspect(
+    video_frame: &ffmpeg_next::frame::Video,
+    stream_sample_aspect: Option<ffmpeg_next::Rational>,
+) -> f64 {
+    let is_valid = |sar: &ffmpeg_next::Rational| sar.numerator() > 0 && sar.denominator() > 0;
+    let sample_aspect = stream_sample_aspect
+        .filter(is_valid)
+        .or(Some(video_frame.aspect_ratio()).filter(is_valid))
+        .map_or(1., f64::from);
+    video_frame.width() as f64 * sample_aspect / video_frame.height().max(1) as f64
+}
 
-            gl.bind_buffer(glow::ARRAY_BUFFER, Some(self.vbo)); 
-            gl.bind_vertex_array(Some(self.vao));
-            gl.draw_arrays(glow::TRIANGLES, 0, 6);
+/// The viewport (x, y, width, height) that shows video of `video_aspect` in a
+/// `window_width`x`window_height` window. It extends past the window when cropping.
+fn video_viewport(
+    window_width: u32,
+    window_height: u32,
+    video_aspect: f64,
+    scale_mode: ScaleMode,
+) -> (i32, i32, i32, i32) {
+    let (window_width, window_height) = (window_width as f64, window_height.max(1) as f64);
+    let window_aspect = window_width / window_height;
 
+    let fit_width = if video_aspect > window_aspect { window_width } else { window_height * video_aspect };
+    let fill_width = if video_aspect > window_aspect { window_height * video_aspect } else { window_width };
+    let (width, height) = match scale_mode {
+        ScaleMode::Stretch => (window_width, window_height),
+        ScaleMode::Fit => (fit_width, fit_width / video_aspect),
+        ScaleMode::Fill => (fill_width, fill_width / video_aspect),
+        ScaleMode::Zoom => {
+            let width = (fit_width + fill_width) / 2.;
+            (width, width / video_aspect)
         }
+    };
+
+    (
+        ((window_width - width) / 2.).round() as i32,
+        ((window_height - height) / 2.).round() as i32,
+        width.round() as i32,
+        height.round() as i32,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 720x480 DVD frame, anamorphic 16:9 with a sample aspect ratio of 32:27.
+    fn anamorphic_frame(sample_aspect: ffmpeg_next::Rational) -> ffmpeg_next::frame::Video {
+        let mut frame = ffmpeg_next::frame::Video::new(ffmpeg_next::format::Pixel::YUV420P, 720, 480);
+        unsafe { (*frame.as_mut_ptr()).sample_aspect_ratio = sample_aspect.into() };
+        frame
+    }
+
+    #[test]
+    fn displays_frames_with_the_sample_aspect_ratio_of_the_stream() {
+        let widescreen = ffmpeg_next::Rational::new(32, 27);
+        let square = ffmpeg_next::Rational::new(1, 1);
+
+        let aspect = display_aspect(&anamorphic_frame(widescreen), None);
+        assert!((aspect - 16. / 9.).abs() < 1e-9);
+        let aspect = display_aspect(&anamorphic_frame(square), Some(widescreen));
+        assert!((aspect - 16. / 9.).abs() < 1e-9);
+        let aspect = display_aspect(&anamorphic_frame(ffmpeg_next::Rational::new(0, 1)), None);
+        assert!((aspect - 1.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn letterboxes_anamorphic_video_in_a_window_of_another_shape() {
+        let aspect = display_aspect(&anamorphic_frame(ffmpeg_next::Rational::new(32, 27)), None);
+
+        assert_eq!(video_viewport(1440, 1080, aspect, ScaleMode::Fit), (0, 135, 1440, 810));
+        assert_eq!(video_viewport(1440, 1080, aspect, ScaleMode::Fill), (-240, 0, 1920, 1080));
+        assert_eq!(video_viewport(1440, 1080, aspect, ScaleMode::Zoom), (-120, 68, 1680, 945));
+        assert_eq!(video_viewport(1440, 1080, aspect, ScaleMode::Stretch), (0, 0, 1440, 1080));
+        // Pillarboxed in a wider window
+        assert_eq!(video_viewport(2560, 1080, aspect, ScaleMode::Fit), (320, 0, 1920, 1080));
     }
 }
@@ -1,4 +1,3 @@
-use std::sync::{mpsc};
 use glow::HasContext;
 use slint::SharedString;
 
@@ -13,17 +12,23 @@ fn main() {
     let file = String::from("./example/videos/sample_subs.mkv"); // YUV420P
     // let file = String::from("http://commondatastorage.googleapis.com/gtv-videos-bucket/sample/TearsOfSteel.mp4"); // YUV420P
 
-    let ictx = ffmpeg_next::format::input(&file).unwrap();
-    let video_stream = ictx.streams().best(ffmpeg_next::media::Type::Video).unwrap();
-    
-    let codec = ffmpeg_next::codec::context::Context::from_parameters(video_stream.parameters()).unwrap();
-    let video = codec.decoder().video().unwrap();
-    let width = video.width();
-    let height = video.height();
-    let format = video.format();
+    let ictx = ffmpeg_next::format::input(&file).unwrap_or_else(|error| {
+        eprintln!("Could not play {}: {}", file, error);
+        std::process::exit(1);
+    });
 
-    println!("Video resolution: {}x{}", width, height);
-    println!("Video format: {:?}", format);
+    // Files without a video stream are played without the video underlay
+    let video_size = ictx.streams().best(ffmpeg_next::media::Type::Video).and_then(|video_stream| {
+        let codec = ffmpeg_next::codec::context::Context::from_parameters(video_stream.parameters()).ok()?;
+        let video = codec.decoder().video().ok()?;
+        let width = video.width();
+        let height = video.height();
+        let format = video.format();
+
+        println!("Video resolution: {}x{}", width, height);
+        println!("Video format: {:?}", format);
+        Some((width, height))
+    });
 
 
     let app = App::new().unwrap();
@@ -31,12 +36,113 @@ fn main() {
 
     
 
-    let (frame_sender, frame_receiver) = mpsc::channel::<ffmpeg_next::util::frame::Video>();
-    let (subtitle_sender, subtitle_receiver) = mpsc::channel::<ffmpeg_next::Packet>();
+    let subtitle_files = player::find_subtitle_files(std::path::Path::new(&file));
 
-    
+    // Decoding threads can be tuned with SENTOANI_DECODER_THREADS (0 for one per core) and
+    // SENTOANI_DECODER_THREADING (frame or slice)
+    let decoder_threading = player::DecoderThreading {
+        mode: match std::env::var("SENTOANI_DECODER_THREADING").as_deref() {
+            Ok("slice") => player::ThreadingMode::Slice,
+            _ => player::ThreadingMode::Frame,
+        },
+        count: std::env::var("SENTOANI_DECODER_THREADS")
+            .ok()
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(0),
+    };
+
+    let player = player::Player::start(
+        file.clone().into(),
+        subtitle_files,
+        decoder_threading,
+        {
+            let app_weak = app.as_weak();
+            let mut primary_cues: Vec<player::SubtitleCue> = Vec::new();
+            let mut secondary_cues: Vec<player::SubtitleCue> = Vec::new();
+
+            move |slot, event| {
+                let active_cues = match slot {
+                    player::SubtitleSlot::Primary => &mut primary_cues,
+                    player::SubtitleSlot::Secondary => &mut secondary_cues,
+                };
+                match event {
+                    player::SubtitleEvent::Show(cue) => {
+                        // Lines of the same style, e.g. dialogue or song lyrics, stay together
+                        let index = active_cues
+                            .iter()
+                            .rposition(|active_cue| active_cue.style == cue.style)
+                            .map_or(active_cues.len(), |index| index + 1);
+                        active_cues.insert(index, cue);
+                    }
+                    player::SubtitleEvent::Hide(cue) => active_cues.retain(|active_cue| *active_cue != cue),
+                    player::SubtitleEvent::Clear => active_cues.clear(),
+                }
+                let text = SharedString::from(
+                    active_cues.iter().map(|cue| cue.text.as_str()).collect::<Vec<_>>().join("\n"),
+                );
+                app_weak
+                    .upgrade_in_event_loop(move |app| match slot {
+                        player::SubtitleSlot::Primary => app.set_primary_subtitles_text(text),
+                        player::SubtitleSlot::Secondary => app.set_secondary_subtitles_text(text),
+                    })
+                    .unwrap();
+            }
+        },
+        {
+            let app_weak = app.as_weak();
+
+            move |playing| {
+                app_weak.upgrade_in_event_loop(move |app| app.set_playing(playing)).unwrap();
+            }
+        },
+    )
+    .unwrap_or_else(|error| {
+        eprintln!("Could not play {}: {}", file, error);
+        std::process::exit(1);
+    });
+
+    // Track menus list the labels of the selectable tracks, with "Off" in front for subtitles
+    let audio_tracks: Vec<usize> = (0..player.tracks().len())
+        .filter(|track| player.tracks()[*track].kind == player::TrackKind::Audio)
+        .collect();
+    let subtitle_tracks: Vec<usize> = (0..player.tracks().len())
+        .filter(|track| player.tracks()[*track].kind == player::TrackKind::Subtitle)
+        .collect();
+    let track_labels = |tracks: &[usize]| -> Vec<SharedString> {
+        tracks.iter().map(|track| SharedString::from(player.tracks()[*track].label())).collect()
+    };
+
+    app.set_audio_tracks(std::rc::Rc::new(slint::VecModel::from(track_labels(&audio_tracks))).into());
+    app.set_audio_track_index(
+        player
+            .audio_track()
+            .and_then(|track| audio_tracks.iter().position(|audio_track| *audio_track == track))
+            .map_or(-1, |index| index as i32),
+    );
+
+    let audio_devices: Vec<player::OutputDevice> = std::iter::once(player::OutputDevice::Default)
+        .chain(player::output_device_names().into_iter().map(player::OutputDevice::Named))
+        .chain(std::iter::once(player::OutputDevice::Silent))
+        .collect();
+    let audio_device_labels: Vec<SharedString> = audio_devices
+        .iter()
+        .map(|device| match device {
+            player::OutputDevice::Default => SharedString::from("Default output"),
+            player::OutputDevice::Named(name) => SharedString::from(name.as_str()),
+            player::OutputDevice::Silent => SharedString::from("Silent"),
+        })
+        .collect();
+    app.set_audio_devices(std::rc::Rc::new(slint::VecModel::from(audio_device_labels)).into());
+    app.set_audio_delay(player.audio_delay());
+
+    let subtitle_labels: Vec<SharedString> =
+        std::iter::once(SharedString::from("Off")).chain(track_labels(&subtitle_tracks)).collect();
+    app.set_subtitle_tracks(std::rc::Rc::new(slint::VecModel::from(subtitle_labels)).into());
+    update_subtitle_track_indices(&app, &player, &subtitle_tracks);
 
     let mut video_underlay = None;
+    let frame_queue = player.frame_queue();
+    let video_sample_aspect_ratio = player.video_sample_aspect_ratio();
 
     if let Err(error) = app
         .window()
@@ -50,21 +156,49 @@ fn main() {
                             },
                             _ => return,
                         };
-                        video_underlay = Some(gl_utils::GLTextureYUV::new(gl_context, width, height));
+                        if let Some((width, height)) = video_size {
+                            video_underlay = gl_utils::GLVideoRenderer::new(gl_context, width, height)
+                                .map_err(|error| eprintln!("Cannot set up video rendering: {}", error))
+                                .ok();
+                            if let Some(underlay) = video_underlay.as_mut() {
+                                underlay.set_stream_sample_aspect_ratio(video_sample_aspect_ratio);
+                            }
+                        }
                     }
                     slint::RenderingState::BeforeRendering => {
                         if let (Some(underlay), Some(app)) = (video_underlay.as_mut(), app_weak.upgrade()) {
-                            // Receive the latest frame from the video thread
-                            if let Ok(new_frame) = frame_receiver.try_recv() {
+                            // Take the latest due frame from the video thread
+                            if let Some(new_frame) = frame_queue.pop() {
                                 // Update the GL texture with the new frame data
-                                underlay.read_video_frame(&new_frame);
+                                if let Some(geometry) = underlay.read_video_frame(&new_frame) {
+                                    app.set_video_geometry(SharedString::from(format!(
+                                        "{}x{} {:?}",
+                                        geometry.width, geometry.height, geometry.format
+                                    )));
+                                }
                             }
-                            underlay.render();
+                            app.set_frame_statistics(SharedString::from(format!(
+                                "{} presented, {} dropped, decoding {:.1} ms",
+                                frame_queue.presented_frames(),
+                                frame_queue.dropped_frames(),
+                                frame_queue.decode_time().as_secs_f64() * 1000.
+                            )));
+                            app.set_hdr(underlay.is_hdr());
+                            underlay.set_tone_mapping(match app.get_tone_mapping().as_str() {
+                                "Hable" => gl_utils::ToneMapping::Hable,
+                                "Reinhard" => gl_utils::ToneMapping::Reinhard,
+                                _ => gl_utils::ToneMapping::BT2390,
+                            });
+                            underlay.set_scale_mode(match app.get_scale_mode().as_str() {
+                                "Fill" => gl_utils::ScaleMode::Fill,
+                                "Stretch" => gl_utils::ScaleMode::Stretch,
+                                "Zoom" => gl_utils::ScaleMode::Zoom,
+                                _ => gl_utils::ScaleMode::Fit,
+                            });
+                            let window_size = app.window().size();
+                            underlay.render(window_size.width, window_size.height);
                             app.window().request_redraw();
                         }
-                        if let (Some(app), Some(subtitle)) = (app_weak.upgrade(), subtitle_receiver.try_recv().ok()) {
-                            app.set_subtitles_text(SharedString::from(String::from_utf8(subtitle.data().unwrap().to_vec()).unwrap()));
-                        }
                     }
                     slint::RenderingState::AfterRendering => {}
                     slint::RenderingState::RenderingTeardown => {
@@ -84,29 +218,107 @@ fn main() {
         std::process::exit(1);
     }
 
-    let mut player = player::Player::start(
-        file.into(),
-        move |new_frame| {
-            let _ = frame_sender.send(new_frame.clone());
-        },
-        move |subtitle| {
-            if subtitle.data().unwrap().len() == 0 {
-                return;
+    let player = std::rc::Rc::new(std::cell::RefCell::new(player));
+
+    app.on_toggle_pause_play({
+        let player = player.clone();
+        move || {
+            player.borrow_mut().toggle_pause_playing();
+        }
+    });
+
+    app.on_seek_relative({
+        let player = player.clone();
+        move |offset| {
+            player.borrow().seek(player::SeekTarget::Relative(offset as f64));
+        }
+    });
+
+    app.on_seek_absolute({
+        let player = player.clone();
+        move |position| {
+            player.borrow().seek(player::SeekTarget::Absolute(position as f64));
+        }
+    });
+
+    app.on_audio_track_selected({
+        let player = player.clone();
+        move |index| {
+            if let Some(track) = audio_tracks.get(index as usize) {
+                player.borrow_mut().select_audio_track(*track);
             }
-            let _ = subtitle_sender.send(subtitle.clone());
-        },
-        {
-            let app_weak = app.as_weak();
+        }
+    });
 
-            move |playing| {
-                app_weak.upgrade_in_event_loop(move |app| app.set_playing(playing)).unwrap();
+    app.on_audio_device_selected({
+        let player = player.clone();
+        move |index| {
+            if let Some(device) = audio_devices.get(index as usize) {
+                player.borrow_mut().select_output_device(device.clone());
             }
-        },
-    )
-    .unwrap();
+        }
+    });
 
-    app.on_toggle_pause_play(move || {
-        player.toggle_pause_playing();
+    app.on_volume_changed({
+        let player = player.clone();
+        move |volume| {
+            player.borrow().audio_settings().set_volume(volume / 100. * player::MAX_VOLUME);
+        }
+    });
+
+    app.on_muted_toggled({
+        let player = player.clone();
+        move |muted| {
+            player.borrow().audio_settings().set_muted(muted);
+        }
+    });
+
+    app.on_loudness_normalization_toggled({
+        let player = player.clone();
+        move |loudness_normalization| {
+            player.borrow().audio_settings().set_loudness_normalization(loudness_normalization);
+        }
+    });
+
+    app.on_dialogue_boost_toggled({
+        let player = player.clone();
+        move |dialogue_boost| {
+            player.borrow().audio_settings().set_dialogue_boost(dialogue_boost);
+        }
+    });
+
+    app.on_audio_delay_changed({
+        let player = player.clone();
+        move |audio_delay| {
+            player.borrow_mut().set_audio_delay(audio_delay);
+        }
+    });
+
+    app.on_subtitle_track_selected({
+        let player = player.clone();
+        let app_weak = app.as_weak();
+        move |primary, index| {
+            let slot =
+                if primary { player::SubtitleSlot::Primary } else { player::SubtitleSlot::Secondary };
+            // Index 0 is "Off"
+            let track = (index as usize).checked_sub(1).and_then(|index| subtitle_tracks.get(index));
+            let mut player = player.borrow_mut();
+            player.select_subtitle_track(slot, track.copied());
+
+            // Selecting the track of the other slot moves it
+            if let Some(app) = app_weak.upgrade() {
+                update_subtitle_track_indices(&app, &player, &subtitle_tracks);
+            }
+        }
+    });
+
+    app.on_playback_rate_selected({
+        let player = player.clone();
+        move |value| {
+            if let Ok(rate) = value.trim_end_matches('x').parse::<f64>() {
+                player.borrow().set_rate(rate);
+            }
+        }
     });
 
     
@@ -114,3 +326,15 @@ fn main() {
 
     app.run().unwrap();
 }
+
+/// Selects the shown subtitle tracks in the track menus, whose first entry is "Off".
+fn update_subtitle_track_indices(app: &App, player: &player::Player, subtitle_tracks: &[usize]) {
+    let menu_index = |slot| {
+        player
+            .subtitle_track(slot)
+            .and_then(|track| subtitle_tracks.iter().position(|subtitle_track| *subtitle_track == track))
+            .map_or(0, |index| index as i32 + 1)
+    };
+    app.set_primary_subtitle_track_index(menu_index(player::SubtitleSlot::Primary));
+    app.set_secondary_subtitle_track_index(menu_index(player::SubtitleSlot::Secondary));
+}
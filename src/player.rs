@@ -1,113 +1,483 @@
 // Copyright © SixtyFPS GmbH <info@slint.dev>
 // SPDX-License-Identifier: MIT
 
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 
-use futures::{future::OptionFuture, FutureExt};
+use futures::{
+    future::{Fuse, FusedFuture},
+    FutureExt,
+};
 
 mod audio;
+mod audio_delays;
+mod clock;
+mod frame_queue;
 mod video;
 mod subtitles;
+mod subtitle_files;
+mod tracks;
+
+pub use frame_queue::{FrameQueue, PooledFrame};
+pub use subtitles::{SubtitleCue, SubtitleEvent};
+pub use subtitle_files::find_subtitle_files;
+pub use tracks::{Track, TrackKind, TrackSource};
+pub use audio::{output_device_names, AudioSettings, OutputDevice, MAX_VOLUME};
+pub use video::{DecoderThreading, ThreadingMode};
 
 #[derive(Clone, Copy)]
 pub enum ControlCommand {
     Play,
     Pause,
+    Seek(SeekTarget),
+    /// Change the playback speed, 1.0 being normal speed. Audio is time-stretched to keep its pitch.
+    SetRate(f64),
+    /// Play the audio the given number of seconds later than the video, or earlier if negative.
+    SetAudioDelay(f64),
+    /// Switch to the audio track with the given index into [`Player::tracks`].
+    SelectAudioTrack(usize),
+    /// Switch the audio to the output device of the audio settings.
+    ReopenAudioOutput,
+    /// Show the subtitle track with the given index into [`Player::tracks`] in a slot, or hide it.
+    SelectSubtitleTrack(SubtitleSlot, Option<usize>),
+}
+
+/// The commands the demuxer passes on to the playback threads. Everything else is handled by the
+/// demuxer: seeks arrive in-band as [`PacketMessage::Flush`], rate and audio delay changes
+/// through the playback clock, and track switches restart the threads.
+#[derive(Clone, Copy)]
+pub enum PlaybackCommand {
+    Play,
+    Pause,
+}
+
+pub const MIN_PLAYBACK_RATE: f64 = 0.25;
+pub const MAX_PLAYBACK_RATE: f64 = 4.0;
+
+/// Largest audio delay in either direction, in milliseconds. The packet queues have to hold
+/// audio and video that far apart, see [`PACKET_QUEUE_DURATION`].
+pub const MAX_AUDIO_DELAY_MS: i32 = 1000;
+
+/// Seconds of packets queued for the audio and the video thread. The demuxer reads ahead of what
+/// is presented by up to this much, which has to cover the lookahead of the loudness
+/// normalization and the audio delay, or the threads end up waiting on each other.
+const PACKET_QUEUE_DURATION: f64 = 5.;
+
+/// Packets per second assumed for streams that don't say, as many as with 20 ms audio packets.
+const DEFAULT_PACKET_RATE: f64 = 50.;
+
+/// The number of packets that make up [`PACKET_QUEUE_DURATION`] of a stream with `packet_rate`
+/// packets per second. Rates that aren't finite and positive count as [`DEFAULT_PACKET_RATE`].
+fn packet_queue_capacity(packet_rate: f64) -> usize {
+    let packet_rate =
+        if packet_rate.is_finite() && packet_rate > 0. { packet_rate } else { DEFAULT_PACKET_RATE };
+    (PACKET_QUEUE_DURATION * packet_rate).ceil() as usize
+}
+
+/// Where to seek to, in seconds.
+#[derive(Clone, Copy, Debug)]
+pub enum SeekTarget {
+    Absolute(f64),
+    /// Offset from the current playback position.
+    Relative(f64),
+}
+
+/// The two subtitle lines shown at the same time, e.g. the target language and a translation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubtitleSlot {
+    Primary,
+    Secondary,
+}
+
+/// Messages sent from the demuxer to the playback threads, in stream order.
+pub enum PacketMessage {
+    Packet(ffmpeg_next::codec::packet::packet::Packet),
+    /// Reset the decoder after a seek and drop any decoded output before the given position
+    /// (in seconds).
+    Flush(f64),
+}
+
+/// The playback thread a demuxed packet is handed to. The threads are reference counted so that
+/// the demuxer can replace them while a packet is still being forwarded.
+enum PacketTarget {
+    Video(Arc<video::VideoPlaybackThread>),
+    Audio(Arc<audio::AudioPlaybackThread>),
+    Subtitles(Arc<subtitles::SubtitlesPlaybackThread>),
 }
 
+impl PacketTarget {
+    async fn receive_packet(self, packet: ffmpeg_next::Packet) {
+        match self {
+            PacketTarget::Video(thread) => {
+                thread.receive_packet(packet).await;
+            }
+            PacketTarget::Audio(thread) => {
+                thread.receive_packet(packet).await;
+            }
+            PacketTarget::Subtitles(thread) => {
+                thread.receive_packet(packet).await;
+            }
+        }
+    }
+}
+
+/// A playback thread of a selected track, with the index of that track and of the stream it
+/// is fed from (`None` for external subtitle files).
+struct ActiveTrack<T> {
+    track: usize,
+    stream_index: Option<usize>,
+    thread: Arc<T>,
+}
+
+/// The subtitle callback passed to [`Player::start`], shared by the subtitle threads of both
+/// slots.
+type SubtitleEventCallback = Arc<Mutex<dyn FnMut(SubtitleSlot, SubtitleEvent) + Send>>;
+
 pub struct Player {
     control_sender: smol::channel::Sender<ControlCommand>,
     demuxer_thread: Option<std::thread::JoinHandle<()>>,
     playing: bool,
     playing_changed_callback: Box<dyn Fn(bool)>,
+    frame_queue: Arc<FrameQueue>,
+    video_sample_aspect_ratio: Option<ffmpeg_next::Rational>,
+    audio_settings: Arc<AudioSettings>,
+    path: PathBuf,
+    audio_delays: audio_delays::AudioDelays,
+    audio_delay: i32,
+    tracks: Vec<Track>,
+    audio_track: Option<usize>,
+    subtitle_tracks: [Option<usize>; 2],
 }
 
 impl Player {
     pub fn start(
         path: PathBuf,
-        video_frame_callback: impl FnMut(&ffmpeg_next::util::frame::Video) + Send + 'static,
-        subtitles_frame_callback: impl FnMut(&ffmpeg_next::Packet) + Send + 'static,
+        subtitle_files: Vec<PathBuf>,
+        decoder_threading: DecoderThreading,
+        subtitle_event_callback: impl FnMut(SubtitleSlot, SubtitleEvent) + Send + 'static,
         playing_changed_callback: impl Fn(bool) + 'static,
     ) -> Result<Self, anyhow::Error> {
         let (control_sender, control_receiver) = smol::channel::unbounded();
 
-        let demuxer_thread =
-            std::thread::Builder::new().name("demuxer thread".into()).spawn(move || {
+        let clock = Arc::new(clock::PlaybackClock::new());
+        let frame_queue = Arc::new(FrameQueue::new(clock.clone()));
+        let audio_settings = Arc::new(AudioSettings::default());
+
+        // Files that needed an audio delay get it again
+        let audio_delays = audio_delays::AudioDelays::load();
+        let audio_delay = audio_delays.get(&path);
+        clock.set_audio_delay(audio_delay as f64 / 1000.);
+
+        let input_context = ffmpeg_next::format::input(&path)?;
+
+        let mut tracks = tracks::stream_tracks(&input_context);
+
+        // Frame based subtitle formats (MicroDVD) are timed in video frames
+        let video_fps = input_context
+            .streams()
+            .best(ffmpeg_next::media::Type::Video)
+            .map(|stream| f64::from(stream.avg_frame_rate()))
+            .filter(|fps| fps.is_finite() && *fps > 0.)
+            .unwrap_or(25.);
+
+        // External subtitle files are parsed up front, files we can't read aren't offered at all
+        let mut external_cues = HashMap::new();
+        for subtitle_file in &subtitle_files {
+            match subtitle_files::load_subtitle_file(subtitle_file, video_fps) {
+                // VobSub index files only carry bitmaps, there would be nothing to show
+                Ok(cues) if cues.is_empty() => {
+                    eprintln!("no text subtitles in {}", subtitle_file.display());
+                }
+                Ok(cues) => {
+                    external_cues.insert(tracks.len(), cues);
+                    tracks.push(tracks::subtitle_file_track(subtitle_file, &path));
+                }
+                Err(err) => eprintln!("error loading subtitles: {}", err),
+            }
+        }
+
+        let track_of_best_stream = |media_type| {
+            let stream_index = input_context.streams().best(media_type)?.index();
+            tracks.iter().position(|track| track.source == TrackSource::Stream(stream_index))
+        };
+        let audio_track = track_of_best_stream(ffmpeg_next::media::Type::Audio);
+
+        // External subtitle files take precedence over the embedded tracks, the first two are
+        // shown as the primary and secondary line.
+        let best_subtitles_track = track_of_best_stream(ffmpeg_next::media::Type::Subtitle);
+        let mut subtitle_candidates = (0..tracks.len())
+            .filter(|track| matches!(tracks[*track].source, TrackSource::File(_)))
+            .chain(best_subtitles_track)
+            .chain((0..tracks.len()).filter(|track| {
+                tracks[*track].kind == TrackKind::Subtitle
+                    && matches!(tracks[*track].source, TrackSource::Stream(_))
+                    && Some(*track) != best_subtitles_track
+            }));
+        let subtitle_tracks = [subtitle_candidates.next(), subtitle_candidates.next()];
+
+        // Every playback thread is optional, the demuxer only routes packets to the ones that
+        // exist. A file needs at least something to play though.
+        let video_stream = input_context.streams().best(ffmpeg_next::media::Type::Video);
+        if video_stream.is_none() && audio_track.is_none() {
+            return Err(anyhow::anyhow!("{} has no video or audio stream", path.display()));
+        }
+
+        // Containers may override the aspect ratio of the codec, e.g. with the display size of a
+        // Matroska track
+        let video_sample_aspect_ratio = video_stream
+            .as_ref()
+            .map(|stream| ffmpeg_next::Rational::from(unsafe { (*stream.as_ptr()).sample_aspect_ratio }))
+            .filter(|sar| sar.numerator() > 0 && sar.denominator() > 0);
+
+        let video_playback_thread = match video_stream {
+            Some(video_stream) => {
+                let thread = video::VideoPlaybackThread::start(
+                    &video_stream,
+                    clock.clone(),
+                    frame_queue.clone(),
+                    decoder_threading,
+                )?;
+                Some((video_stream.index(), Arc::new(thread)))
+            }
+            None => None,
+        };
+
+        let audio_playback_thread = audio_track
+            .map(|track| start_audio_track(&input_context, &tracks, track, &clock, &audio_settings))
+            .transpose()?;
+
+        let subtitle_event_callback: SubtitleEventCallback =
+            Arc::new(Mutex::new(subtitle_event_callback));
+
+        // A subtitle track that can't be decoded shouldn't keep the file from playing
+        let subtitle_playback_threads = [SubtitleSlot::Primary, SubtitleSlot::Secondary].map(|slot| {
+            let track = subtitle_tracks[slot as usize]?;
+            start_subtitle_track(
+                &input_context,
+                &tracks,
+                track,
+                external_cues.get(&track),
+                &clock,
+                subtitle_slot_callback(&subtitle_event_callback, slot),
+            )
+            .map_err(|err| eprintln!("error starting subtitle track: {}", err))
+            .ok()
+        });
+        let subtitle_tracks = subtitle_playback_threads
+            .each_ref()
+            .map(|subtitles| subtitles.as_ref().map(|subtitles| subtitles.track));
+
+        if audio_playback_thread.is_none() {
+            clock.resume_with_wall(0.);
+        }
+
+        let demuxer_thread = std::thread::Builder::new().name("demuxer thread".into()).spawn({
+            let tracks = tracks.clone();
+            let audio_settings = audio_settings.clone();
+            move || {
                 smol::block_on(async move {
-                    let mut input_context = ffmpeg_next::format::input(&path).unwrap();
-
-                    let video_stream =
-                        input_context.streams().best(ffmpeg_next::media::Type::Video).unwrap();
-                    let video_stream_index = video_stream.index();
-                    let video_playback_thread = video::VideoPlaybackThread::start(
-                        &video_stream,
-                        Box::new(video_frame_callback),
-                    )
-                    .unwrap();
-
-                    let audio_stream =
-                        input_context.streams().best(ffmpeg_next::media::Type::Audio).unwrap();
-                    let audio_stream_index = audio_stream.index();
-                    let audio_playback_thread =
-                        audio::AudioPlaybackThread::start(&audio_stream).unwrap();
-
-                    let subtitles_stream = input_context.streams().best(ffmpeg_next::media::Type::Subtitle).unwrap();
-                    let subtitles_stream_index = subtitles_stream.index();
-                    let subtitles_playback_thread = subtitles::SubtitlesPlaybackThread::start(
-                        &subtitles_stream, 
-                        Box::new(subtitles_frame_callback)
-                    ).unwrap();
-                    
+                    let mut input_context = input_context;
+                    let mut audio_playback_thread = audio_playback_thread;
+                    let mut subtitle_playback_threads = subtitle_playback_threads;
 
                     let mut playing = true;
+                    let mut end_of_stream = false;
+                    // Set by seeks and by track switches that need the packets from the current
+                    // position again.
+                    let mut pending_seek: Option<f64> = None;
 
-                    // This is sub-optimal, as reading the packets from ffmpeg might be blocking
-                    // and the future won't yield for that. So while ffmpeg sits on some blocking
-                    // I/O operation, the caller here will also block and we won't end up polling
-                    // the control_receiver future further down.
-                    let packet_forwarder_impl = async {
-                        for (stream, packet) in input_context.packets() {
-                            if stream.index() == audio_stream_index {
-                                audio_playback_thread.receive_packet(packet).await;
-                            } else if stream.index() == video_stream_index {
-                                video_playback_thread.receive_packet(packet).await;
-                            } else if stream.index() == subtitles_stream_index {
-                                subtitles_playback_thread.receive_packet(packet).await;
-                            }
-                        }
-                    }
-                    .fuse()
-                    .shared();
+                    // The packet currently being handed to one of the playback threads. It is kept
+                    // across loop iterations so that a full packet queue doesn't stop us from
+                    // reacting to control commands, and dropped when seeking.
+                    let packet_forwarder = Fuse::terminated();
+                    smol::pin!(packet_forwarder);
 
                     loop {
-                        // This is sub-optimal, as reading the packets from ffmpeg might be blocking
-                        // and the future won't yield for that. So while ffmpeg sits on some blocking
-                        // I/O operation, the caller here will also block and we won't end up polling
-                        // the control_receiver future further down.
-                        let packet_forwarder: OptionFuture<_> =
-                            if playing { Some(packet_forwarder_impl.clone()) } else { None }.into();
+                        if let Some(position) = pending_seek.take() {
+                            // The video is presented that much ahead of the audio
+                            let video_position = match &audio_playback_thread {
+                                Some(_) => (position + clock.audio_delay()).max(0.),
+                                None => position,
+                            };
+
+                            // Seek to the closest key frame before the requested position of
+                            // both, the playback threads drop everything decoded before theirs.
+                            let timestamp = (position.min(video_position)
+                                * ffmpeg_next::ffi::AV_TIME_BASE as f64) as i64;
+                            match input_context.seek(timestamp, ..timestamp) {
+                                Ok(()) => {
+                                    packet_forwarder.set(Fuse::terminated());
+                                    end_of_stream = false;
 
-                        smol::pin!(packet_forwarder);
+                                    // Hold the clock until the audio thread resumes it with
+                                    // the first samples after the seek, or run it on the wall
+                                    // clock right away without audio.
+                                    clock.reset(position);
+
+                                    if let Some((_, video)) = &video_playback_thread {
+                                        video.flush(video_position).await;
+                                    }
+                                    match &audio_playback_thread {
+                                        Some(audio) => audio.thread.flush(position).await,
+                                        None => clock.resume_with_wall(position),
+                                    }
+                                    for subtitles in subtitle_playback_threads.iter().flatten() {
+                                        subtitles.thread.flush(position).await;
+                                    }
+                                }
+                                Err(error) => {
+                                    eprintln!("error seeking to {:.3}s: {}", position, error);
+                                }
+                            }
+                        }
+
+                        if playing && !end_of_stream && packet_forwarder.is_terminated() {
+                            // This is sub-optimal, as reading the packets from ffmpeg might be blocking.
+                            // So while ffmpeg sits on some blocking I/O operation, we won't end up
+                            // polling the control_receiver future further down.
+                            match input_context.packets().next() {
+                                Some((stream, packet)) => {
+                                    let stream_index = Some(stream.index());
+                                    let target = if let Some((_, video)) = video_playback_thread
+                                        .as_ref()
+                                        .filter(|(video_stream_index, _)| Some(*video_stream_index) == stream_index)
+                                    {
+                                        Some(PacketTarget::Video(video.clone()))
+                                    } else if let Some(audio) = audio_playback_thread
+                                        .as_ref()
+                                        .filter(|audio| audio.stream_index == stream_index)
+                                    {
+                                        Some(PacketTarget::Audio(audio.thread.clone()))
+                                    } else {
+                                        subtitle_playback_threads
+                                            .iter()
+                                            .flatten()
+                                            .find(|subtitles| subtitles.stream_index == stream_index)
+                                            .map(|subtitles| {
+                                                PacketTarget::Subtitles(subtitles.thread.clone())
+                                            })
+                                    };
+                                    if let Some(target) = target {
+                                        packet_forwarder.set(target.receive_packet(packet).fuse());
+                                    }
+                                }
+                                None => end_of_stream = true, // playback finished
+                            }
+                        }
 
                         futures::select! {
-                            _ = packet_forwarder => {}, // playback finished
+                            _ = packet_forwarder => {},
                             received_command = control_receiver.recv().fuse() => {
                                 match received_command {
-                                    Ok(command) => {
-                                        video_playback_thread.send_control_message(command).await;
-                                        audio_playback_thread.send_control_message(command).await;
-                                        subtitles_playback_thread.send_control_message(command).await;
-                                        match command {
-                                            ControlCommand::Play => {
-                                                // Continue in the loop, polling the packet forwarder future to forward
-                                                // packets
-                                                playing = true;
-                                            },
-                                            ControlCommand::Pause => {
-                                                playing = false;
+                                    Ok(ControlCommand::Seek(target)) => {
+                                        let position = match target {
+                                            SeekTarget::Absolute(position) => position,
+                                            SeekTarget::Relative(offset) => clock.audio_position() + offset,
+                                        };
+                                        pending_seek = Some(position.max(0.));
+                                    }
+                                    Ok(ControlCommand::SetRate(rate)) => {
+                                        clock.set_rate(rate.clamp(MIN_PLAYBACK_RATE, MAX_PLAYBACK_RATE));
+                                    }
+                                    Ok(ControlCommand::SetAudioDelay(audio_delay)) => {
+                                        clock.set_audio_delay(audio_delay);
+                                    }
+                                    Ok(command @ (ControlCommand::SelectAudioTrack(_) | ControlCommand::ReopenAudioOutput)) => {
+                                        // The current track switches its output in place, unless the
+                                        // device needs another format, which restarts the track
+                                        let track = match command {
+                                            ControlCommand::SelectAudioTrack(track) => Some(track),
+                                            _ => audio_playback_thread
+                                                .as_ref()
+                                                .filter(|audio| {
+                                                    !audio.thread.switch_output(&audio_settings.output_device())
+                                                })
+                                                .map(|audio| audio.track),
+                                        };
+                                        if let Some(track) = track {
+                                            // The packet being forwarded may hold on to the old thread,
+                                            // which has to be gone before the new one opens the device.
+                                            packet_forwarder.set(Fuse::terminated());
+                                            audio_playback_thread = None;
+                                            audio_playback_thread =
+                                                start_audio_track(&input_context, &tracks, track, &clock, &audio_settings)
+                                                    .map_err(|err| eprintln!("error starting audio track: {}", err))
+                                                    .ok();
+                                            if !playing {
+                                                if let Some(audio) = &audio_playback_thread {
+                                                    audio.thread.send_control_message(PlaybackCommand::Pause).await;
+                                                }
                                             }
+                                            // Read again from the current position, the packets of the
+                                            // new track up to here were skipped and the old thread's
+                                            // buffered samples are gone.
+                                            pending_seek = Some(clock.audio_position());
                                         }
                                     }
+                                    Ok(ControlCommand::SelectSubtitleTrack(slot, track)) => {
+                                        packet_forwarder.set(Fuse::terminated());
+
+                                        // A track can only be shown in one slot
+                                        let other_slot = match slot {
+                                            SubtitleSlot::Primary => SubtitleSlot::Secondary,
+                                            SubtitleSlot::Secondary => SubtitleSlot::Primary,
+                                        };
+                                        for current_slot in [slot, other_slot] {
+                                            let shown = &mut subtitle_playback_threads[current_slot as usize];
+                                            let replaced = current_slot == slot
+                                                || (track.is_some()
+                                                    && shown.as_ref().map(|subtitles| subtitles.track) == track);
+                                            if let Some(previous) = shown.take_if(|_| replaced) {
+                                                // Join the thread before clearing, so that it can't
+                                                // show anything afterwards.
+                                                drop(previous);
+                                                (*subtitle_event_callback.lock().unwrap())(
+                                                    current_slot,
+                                                    SubtitleEvent::Clear,
+                                                );
+                                            }
+                                        }
+
+                                        let subtitles = track.and_then(|track| {
+                                            start_subtitle_track(
+                                                &input_context,
+                                                &tracks,
+                                                track,
+                                                external_cues.get(&track),
+                                                &clock,
+                                                subtitle_slot_callback(&subtitle_event_callback, slot),
+                                            )
+                                            .map_err(|err| eprintln!("error starting subtitle track: {}", err))
+                                            .ok()
+                                        });
+                                        if let Some(subtitles) = &subtitles {
+                                            if !playing {
+                                                subtitles.thread.send_control_message(PlaybackCommand::Pause).await;
+                                            }
+                                            // Embedded cues up to here were skipped, external ones are
+                                            // all loaded already.
+                                            if subtitles.stream_index.is_some() {
+                                                pending_seek = Some(clock.audio_position());
+                                            }
+                                        }
+                                        subtitle_playback_threads[slot as usize] = subtitles;
+                                    }
+                                    Ok(command @ (ControlCommand::Play | ControlCommand::Pause)) => {
+                                        // While playing, continue in the loop, reading and forwarding packets
+                                        playing = matches!(command, ControlCommand::Play);
+                                        let command = if playing { PlaybackCommand::Play } else { PlaybackCommand::Pause };
+                                        if let Some((_, video)) = &video_playback_thread {
+                                            video.send_control_message(command).await;
+                                        }
+                                        if let Some(audio) = &audio_playback_thread {
+                                            audio.thread.send_control_message(command).await;
+                                        }
+                                        for subtitles in subtitle_playback_threads.iter().flatten() {
+                                            subtitles.thread.send_control_message(command).await;
+                                        }
+                                        clock.set_paused(!playing);
+                                    }
                                     Err(_) => {
                                         // Channel closed -> quit
                                         return;
@@ -117,7 +487,8 @@ impl Player {
                         }
                     }
                 })
-            })?;
+            }
+        })?;
 
         let playing = true;
         playing_changed_callback(playing);
@@ -127,6 +498,15 @@ impl Player {
             demuxer_thread: Some(demuxer_thread),
             playing,
             playing_changed_callback: Box::new(playing_changed_callback),
+            frame_queue,
+            video_sample_aspect_ratio,
+            audio_settings,
+            path,
+            audio_delays,
+            audio_delay,
+            tracks,
+            audio_track,
+            subtitle_tracks,
         })
     }
 
@@ -140,6 +520,105 @@ impl Player {
         }
         (self.playing_changed_callback)(self.playing);
     }
+
+    pub fn seek(&self, target: SeekTarget) {
+        self.control_sender.send_blocking(ControlCommand::Seek(target)).unwrap();
+    }
+
+    pub fn set_rate(&self, rate: f64) {
+        self.control_sender.send_blocking(ControlCommand::SetRate(rate)).unwrap();
+    }
+
+    /// Milliseconds the audio is played later than the video, negative if earlier.
+    pub fn audio_delay(&self) -> i32 {
+        self.audio_delay
+    }
+
+    /// Shifts the audio against the video by `audio_delay` milliseconds, e.g. for encodes with
+    /// out of sync audio. The delay is remembered for the file.
+    pub fn set_audio_delay(&mut self, audio_delay: i32) {
+        let audio_delay = audio_delay.clamp(-MAX_AUDIO_DELAY_MS, MAX_AUDIO_DELAY_MS);
+        if self.audio_delay == audio_delay {
+            return;
+        }
+        self.audio_delay = audio_delay;
+        self.control_sender
+            .send_blocking(ControlCommand::SetAudioDelay(audio_delay as f64 / 1000.))
+            .unwrap();
+        if let Err(err) = self.audio_delays.set(&self.path, audio_delay) {
+            eprintln!("error storing the audio delay: {}", err);
+        }
+    }
+
+    /// Where the decoded video frames are delivered to, for the renderer.
+    pub fn frame_queue(&self) -> Arc<FrameQueue> {
+        self.frame_queue.clone()
+    }
+
+    /// The sample aspect ratio the container sets for the video stream, which takes precedence
+    /// over that of the decoded frames.
+    pub fn video_sample_aspect_ratio(&self) -> Option<ffmpeg_next::Rational> {
+        self.video_sample_aspect_ratio
+    }
+
+    /// Audio settings that apply to every audio track.
+    pub fn audio_settings(&self) -> &AudioSettings {
+        &self.audio_settings
+    }
+
+    /// The video, audio and subtitle streams of the file, followed by the external subtitle files.
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    /// The index into [`Player::tracks`] of the audio track being played.
+    pub fn audio_track(&self) -> Option<usize> {
+        self.audio_track
+    }
+
+    /// The index into [`Player::tracks`] of the subtitle track shown in `slot`.
+    pub fn subtitle_track(&self, slot: SubtitleSlot) -> Option<usize> {
+        self.subtitle_tracks[slot as usize]
+    }
+
+    pub fn select_audio_track(&mut self, track: usize) {
+        if self.tracks.get(track).map(|track| track.kind) != Some(TrackKind::Audio)
+            || self.audio_track == Some(track)
+        {
+            return;
+        }
+        self.audio_track = Some(track);
+        self.control_sender.send_blocking(ControlCommand::SelectAudioTrack(track)).unwrap();
+    }
+
+    /// Plays audio on `output_device` from now on. Playback carries on uninterrupted if the
+    /// device can play the current audio format, otherwise the audio track is restarted in the
+    /// device's format, which re-buffers from the current position like a seek.
+    pub fn select_output_device(&mut self, output_device: OutputDevice) {
+        if self.audio_settings.output_device() == output_device {
+            return;
+        }
+        self.audio_settings.set_output_device(output_device);
+        self.control_sender.send_blocking(ControlCommand::ReopenAudioOutput).unwrap();
+    }
+
+    /// Shows the given subtitle track in `slot`, or nothing for `None`. A track already shown in
+    /// the other slot is moved.
+    pub fn select_subtitle_track(&mut self, slot: SubtitleSlot, track: Option<usize>) {
+        if track.is_some_and(|track| {
+            self.tracks.get(track).map(|track| track.kind) != Some(TrackKind::Subtitle)
+        }) || self.subtitle_tracks[slot as usize] == track
+        {
+            return;
+        }
+        for (current_slot, current_track) in self.subtitle_tracks.iter_mut().enumerate() {
+            if current_slot == slot as usize || (track.is_some() && *current_track == track) {
+                *current_track = None;
+            }
+        }
+        self.subtitle_tracks[slot as usize] = track;
+        self.control_sender.send_blocking(ControlCommand::SelectSubtitleTrack(slot, track)).unwrap();
+    }
 }
 
 impl Drop for Player {
@@ -150,3 +629,65 @@ impl Drop for Player {
         }
     }
 }
+
+fn start_audio_track(
+    input_context: &ffmpeg_next::format::context::Input,
+    tracks: &[Track],
+    track: usize,
+    clock: &Arc<clock::PlaybackClock>,
+    settings: &Arc<AudioSettings>,
+) -> Result<ActiveTrack<audio::AudioPlaybackThread>, anyhow::Error> {
+    let stream_index = match tracks.get(track).map(|track| &track.source) {
+        Some(TrackSource::Stream(stream_index)) => *stream_index,
+        _ => return Err(anyhow::anyhow!("{} is not an audio stream", track)),
+    };
+    let stream = input_context
+        .stream(stream_index)
+        .ok_or_else(|| anyhow::anyhow!("no stream with index {}", stream_index))?;
+    let thread = audio::AudioPlaybackThread::start(&stream, clock.clone(), settings.clone())?;
+    Ok(ActiveTrack { track, stream_index: Some(stream_index), thread: Arc::new(thread) })
+}
+
+fn start_subtitle_track(
+    input_context: &ffmpeg_next::format::context::Input,
+    tracks: &[Track],
+    track: usize,
+    external_cues: Option<&Vec<subtitles::SubtitleCue>>,
+    clock: &Arc<clock::PlaybackClock>,
+    subtitle_event_callback: Box<dyn FnMut(SubtitleEvent) + Send>,
+) -> Result<ActiveTrack<subtitles::SubtitlesPlaybackThread>, anyhow::Error> {
+    let (stream_index, thread) = match tracks.get(track).map(|track| &track.source) {
+        Some(TrackSource::Stream(stream_index)) => {
+            let stream = input_context
+                .stream(*stream_index)
+                .ok_or_else(|| anyhow::anyhow!("no stream with index {}", stream_index))?;
+            let thread = subtitles::SubtitlesPlaybackThread::start(
+                &stream,
+                clock.clone(),
+                subtitle_event_callback,
+            )?;
+            (Some(*stream_index), thread)
+        }
+        Some(TrackSource::File(path)) => {
+            let cues = external_cues
+                .ok_or_else(|| anyhow::anyhow!("{} was not loaded", path.display()))?;
+            let thread = subtitles::SubtitlesPlaybackThread::start_external(
+                cues.clone(),
+                clock.clone(),
+                subtitle_event_callback,
+            )?;
+            (None, thread)
+        }
+        None => return Err(anyhow::anyhow!("no track {}", track)),
+    };
+    Ok(ActiveTrack { track, stream_index, thread: Arc::new(thread) })
+}
+
+/// Binds the shared subtitle callback to the track shown in `slot`.
+fn subtitle_slot_callback(
+    subtitle_event_callback: &SubtitleEventCallback,
+    slot: SubtitleSlot,
+) -> Box<dyn FnMut(SubtitleEvent) + Send> {
+    let subtitle_event_callback = subtitle_event_callback.clone();
+    Box::new(move |event| (*subtitle_event_callback.lock().unwrap())(slot, event))
+}
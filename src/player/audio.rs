@@ -1,74 +1,251 @@
 // Copyright © SixtyFPS GmbH <info@slint.dev>
 // SPDX-License-Identifier: MIT
 
+use std::marker::PhantomData;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
 
 use bytemuck::Pod;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::SizedSample;
+use cpal::{FromSample, SizedSample};
 
 use futures::future::OptionFuture;
 use futures::FutureExt;
-use ringbuf::ring_buffer::{RbRef, RbWrite};
-use ringbuf::HeapRb;
+use ringbuf::{HeapProducer, HeapRb};
 use std::future::Future;
 
-use super::ControlCommand;
+use super::{clock::PlaybackClock, PacketMessage, PlaybackCommand};
+
+/// Size of the buffer between the decoder and the output device, in samples per channel.
+const OUTPUT_BUFFER_FRAMES: usize = 2048;
+
+/// Mix levels of the center and surround channels when downmixing with dialogue boost. The
+/// defaults are -3 dB for both, boosting keeps the center at full level and lowers the surrounds
+/// to -6 dB.
+const DIALOGUE_BOOST_CENTER_MIX_LEVEL: f64 = 1.0;
+const DIALOGUE_BOOST_SURROUND_MIX_LEVEL: f64 = 0.5;
+
+/// Targets of the loudness normalization: the integrated loudness in LUFS, the loudness range
+/// in LU and the maximum true peak in dBTP. Louder than the -23 LUFS of broadcast, which is hard
+/// to follow on laptop speakers.
+const LOUDNESS_TARGET: f64 = -16.;
+const LOUDNESS_RANGE_TARGET: f64 = 11.;
+const LOUDNESS_TRUE_PEAK: f64 = -1.5;
+
+/// Highest volume, as a factor of the original.
+pub const MAX_VOLUME: f32 = 1.0;
+
+/// Format of the silent output used when no audio device is available.
+const SILENT_OUTPUT_CHANNELS: u16 = 2;
+const SILENT_OUTPUT_SAMPLE_RATE: u32 = 48000;
+
+/// How often the silent output consumes samples.
+const SILENT_OUTPUT_PERIOD: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// Where audio is played.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum OutputDevice {
+    /// The system's default output device.
+    #[default]
+    Default,
+    /// The output device with this name, see [`output_device_names`].
+    Named(String),
+    /// Nowhere. Playback continues at the same pace, e.g. for headless testing.
+    Silent,
+}
+
+/// The names of the available audio output devices.
+pub fn output_device_names() -> Vec<String> {
+    match cpal::default_host().output_devices() {
+        Ok(devices) => devices.filter_map(|device| device.name().ok()).collect(),
+        Err(err) => {
+            eprintln!("error listing audio output devices: {}", err);
+            Vec::new()
+        }
+    }
+}
+
+/// Audio settings that can be changed during playback and carry over when switching tracks.
+pub struct AudioSettings {
+    /// Volume factor, as f32 bits.
+    volume: AtomicU32,
+    muted: AtomicBool,
+    loudness_normalization: AtomicBool,
+    dialogue_boost: AtomicBool,
+    output_device: Mutex<OutputDevice>,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            volume: AtomicU32::new(MAX_VOLUME.to_bits()),
+            muted: AtomicBool::new(false),
+            loudness_normalization: AtomicBool::new(false),
+            dialogue_boost: AtomicBool::new(false),
+            output_device: Mutex::new(OutputDevice::Default),
+        }
+    }
+}
+
+impl AudioSettings {
+    /// The factor samples are scaled with, from 0 to [`MAX_VOLUME`].
+    pub fn volume(&self) -> f32 {
+        f32::from_bits(self.volume.load(Ordering::Relaxed))
+    }
+
+    pub fn set_volume(&self, volume: f32) {
+        self.volume.store(volume.clamp(0., MAX_VOLUME).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+
+    /// Whether the loudness is evened out, so that quiet and loud scenes are both comfortable.
+    /// Normalizing looks about 3 s ahead: turning it on, starting playback and seeking all hold
+    /// the audio until that much is decoded, which the packet queues leave room for. Rate changes
+    /// keep the normalization running.
+    pub fn loudness_normalization(&self) -> bool {
+        self.loudness_normalization.load(Ordering::Relaxed)
+    }
+
+    pub fn set_loudness_normalization(&self, loudness_normalization: bool) {
+        self.loudness_normalization.store(loudness_normalization, Ordering::Relaxed);
+    }
+
+    /// Whether the center channel, which usually carries the dialogue, is emphasized when
+    /// downmixing surround sound to fewer output channels.
+    pub fn dialogue_boost(&self) -> bool {
+        self.dialogue_boost.load(Ordering::Relaxed)
+    }
+
+    pub fn set_dialogue_boost(&self, dialogue_boost: bool) {
+        self.dialogue_boost.store(dialogue_boost, Ordering::Relaxed);
+    }
+
+    pub fn output_device(&self) -> OutputDevice {
+        self.output_device.lock().unwrap().clone()
+    }
+
+    /// Takes effect through [`super::Player::select_output_device`].
+    pub(super) fn set_output_device(&self, output_device: OutputDevice) {
+        *self.output_device.lock().unwrap() = output_device;
+    }
+}
+
+/// A device the forwarder is to switch its output to, `None` for the silent output.
+type OutputSwitch = Arc<Mutex<Option<Option<cpal::Device>>>>;
 
 pub struct AudioPlaybackThread {
-    control_sender: smol::channel::Sender<ControlCommand>,
-    packet_sender: smol::channel::Sender<ffmpeg_next::codec::packet::packet::Packet>,
+    control_sender: smol::channel::Sender<PlaybackCommand>,
+    packet_sender: smol::channel::Sender<PacketMessage>,
+    packet_drain: smol::channel::Receiver<PacketMessage>,
+    flushing: Arc<AtomicBool>,
+    /// The format the thread plays at.
+    config: cpal::SupportedStreamConfig,
+    output_switch: OutputSwitch,
     receiver_thread: Option<std::thread::JoinHandle<()>>,
 }
 
 impl AudioPlaybackThread {
-    pub fn start(stream: &ffmpeg_next::format::stream::Stream) -> Result<Self, anyhow::Error> {
+    pub fn start(
+        stream: &ffmpeg_next::format::stream::Stream,
+        clock: Arc<PlaybackClock>,
+        settings: Arc<AudioSettings>,
+    ) -> Result<Self, anyhow::Error> {
         let (control_sender, control_receiver) = smol::channel::unbounded();
 
-        let (packet_sender, packet_receiver) = smol::channel::bounded(128);
-
         let decoder_context = ffmpeg_next::codec::Context::from_parameters(stream.parameters())?;
         let packet_decoder = decoder_context.decoder().audio()?;
 
-        let host = cpal::default_host();
-        let device = host.default_output_device().expect("no output device available");
+        // Each packet usually holds a frame of the codec's frame size, which some codecs leave open
+        let packet_queue_capacity =
+            super::packet_queue_capacity(packet_decoder.rate() as f64 / packet_decoder.frame_size() as f64);
+        let (packet_sender, packet_receiver) = smol::channel::bounded(packet_queue_capacity);
+        let packet_drain = packet_receiver.clone();
+
+        let time_base = stream.time_base();
+        let time_base_seconds = time_base.numerator() as f64 / time_base.denominator() as f64;
 
-        let config = device.default_output_config().unwrap();
+        let flushing = Arc::new(AtomicBool::new(false));
+        let thread_flushing = flushing.clone();
+
+        // Without a usable device, play silently rather than not at all
+        let (device, config) = match open_output_device(&settings.output_device()) {
+            Ok(Some((device, config))) => (Some(device), config),
+            Ok(None) => (None, silent_output_config()),
+            Err(err) => {
+                eprintln!("{}, playing without sound", err);
+                (None, silent_output_config())
+            }
+        };
+
+        let output_switch = OutputSwitch::default();
+
+        // The output stream can't be moved between threads on every platform, so the forwarder
+        // is set up on the playback thread, which reports back whether that worked.
+        let (ready_sender, ready_receiver) = std::sync::mpsc::sync_channel(1);
+
+        let thread_config = config.clone();
+        let thread_output_switch = output_switch.clone();
 
         let receiver_thread =
             std::thread::Builder::new().name("audio playback thread".into()).spawn(move || {
                 smol::block_on(async move {
-                    let output_channel_layout = match config.channels() {
-                        1 => ffmpeg_next::util::channel_layout::ChannelLayout::MONO,
-                        2 => {
-                            ffmpeg_next::util::channel_layout::ChannelLayout::STEREO_LEFT
-                                | ffmpeg_next::util::channel_layout::ChannelLayout::STEREO_RIGHT
-                        }
-                        _ => todo!(),
+                    let config = thread_config;
+                    let sample_format = config.sample_format();
+                    let (channel_layout, channel_order) =
+                        output_channels(config.channels(), cpal::default_host().id());
+                    let output = OutputConfig { config, channel_layout, channel_order };
+                    let shared = SharedState {
+                        flushing: thread_flushing,
+                        output_switch: thread_output_switch,
+                        clock,
+                        settings,
                     };
 
-                    let mut ffmpeg_to_cpal_forwarder = match config.sample_format() {
-                        cpal::SampleFormat::U8 => FFmpegToCPalForwarder::new::<u8>(
-                            config,
-                            &device,
-                            packet_receiver,
-                            packet_decoder,
-                            ffmpeg_next::util::format::sample::Sample::U8(
-                                ffmpeg_next::util::format::sample::Type::Packed,
-                            ),
-                            output_channel_layout,
-                        ),
-                        cpal::SampleFormat::F32 => FFmpegToCPalForwarder::new::<f32>(
-                            config,
-                            &device,
-                            packet_receiver,
-                            packet_decoder,
-                            ffmpeg_next::util::format::sample::Sample::F32(
-                                ffmpeg_next::util::format::sample::Type::Packed,
-                            ),
-                            output_channel_layout,
-                        ),
-                        format @ _ => todo!("unsupported cpal output format {:#?}", format),
+                    // ffmpeg has no signed 8-bit or unsigned 16 to 64-bit formats, those are
+                    // converted from the samples of the same size and opposite signedness.
+                    macro_rules! forwarder {
+                        ($cpal_sample:ty, $ffmpeg_sample:ty) => {
+                            FFmpegToCPalForwarder::new::<$cpal_sample, $ffmpeg_sample>(
+                                output,
+                                device.as_ref(),
+                                packet_receiver,
+                                packet_decoder,
+                                time_base_seconds,
+                                shared,
+                            )
+                        };
+                    }
+
+                    let ffmpeg_to_cpal_forwarder = match sample_format {
+                        cpal::SampleFormat::I8 => forwarder!(i8, u8),
+                        cpal::SampleFormat::I16 => forwarder!(i16, i16),
+                        cpal::SampleFormat::I32 => forwarder!(i32, i32),
+                        cpal::SampleFormat::I64 => forwarder!(i64, i64),
+                        cpal::SampleFormat::U8 => forwarder!(u8, u8),
+                        cpal::SampleFormat::U16 => forwarder!(u16, i16),
+                        cpal::SampleFormat::U32 => forwarder!(u32, i32),
+                        cpal::SampleFormat::U64 => forwarder!(u64, i64),
+                        cpal::SampleFormat::F32 => forwarder!(f32, f32),
+                        cpal::SampleFormat::F64 => forwarder!(f64, f64),
+                        format => unreachable!("unsupported cpal output format {} was not rejected", format),
+                    };
+                    let mut ffmpeg_to_cpal_forwarder = match ffmpeg_to_cpal_forwarder {
+                        Ok(ffmpeg_to_cpal_forwarder) => {
+                            let _ = ready_sender.send(Ok(()));
+                            ffmpeg_to_cpal_forwarder
+                        }
+                        Err(err) => {
+                            let _ = ready_sender.send(Err(err));
+                            return;
+                        }
                     };
 
                     let packet_receiver_impl =
@@ -86,10 +263,10 @@ impl AudioPlaybackThread {
                             _ = packet_receiver => {},
                             received_command = control_receiver.recv().fuse() => {
                                 match received_command {
-                                    Ok(ControlCommand::Pause) => {
+                                    Ok(PlaybackCommand::Pause) => {
                                         playing = false;
                                     }
-                                    Ok(ControlCommand::Play) => {
+                                    Ok(PlaybackCommand::Play) => {
                                         playing = true;
                                     }
                                     Err(_) => {
@@ -103,17 +280,55 @@ impl AudioPlaybackThread {
                 })
             })?;
 
-        Ok(Self { control_sender, packet_sender, receiver_thread: Some(receiver_thread) })
+        if let Err(err) = ready_receiver.recv()? {
+            let _ = receiver_thread.join();
+            return Err(err);
+        }
+
+        Ok(Self {
+            control_sender,
+            packet_sender,
+            packet_drain,
+            flushing,
+            config,
+            output_switch,
+            receiver_thread: Some(receiver_thread),
+        })
     }
 
     pub async fn receive_packet(&self, packet: ffmpeg_next::codec::packet::packet::Packet) -> bool {
-        match self.packet_sender.send(packet).await {
+        match self.packet_sender.send(PacketMessage::Packet(packet)).await {
             Ok(_) => return true,
             Err(smol::channel::SendError(_)) => return false,
         }
     }
 
-    pub async fn send_control_message(&self, message: ControlCommand) {
+    /// Discards all queued packets and buffered samples, resuming playback with the first
+    /// frame at or after `position` (in seconds).
+    pub async fn flush(&self, position: f64) {
+        self.flushing.store(true, Ordering::SeqCst);
+        while self.packet_drain.try_recv().is_ok() {}
+        let _ = self.packet_sender.send(PacketMessage::Flush(position)).await;
+    }
+
+    /// Switches to `output_device` while the decoder, filters and buffered samples carry on, if
+    /// the device can play the format the thread plays at. The switch happens with the next
+    /// packet. Returns `false` if the device needs another format, which takes a new thread.
+    pub fn switch_output(&self, output_device: &OutputDevice) -> bool {
+        let device = match open_output_device(output_device) {
+            Ok(Some((device, _))) if supports_config(&device, &self.config) => Some(device),
+            Ok(Some(_)) => return false,
+            Ok(None) => None,
+            Err(err) => {
+                eprintln!("{}, playing without sound", err);
+                None
+            }
+        };
+        *self.output_switch.lock().unwrap() = Some(device);
+        true
+    }
+
+    pub async fn send_control_message(&self, message: PlaybackCommand) {
         self.control_sender.send(message).await.unwrap();
     }
 }
@@ -132,12 +347,104 @@ trait FFMpegToCPalSampleForwarder {
         &mut self,
         audio_frame: ffmpeg_next::frame::Audio,
     ) -> Pin<Box<dyn Future<Output = ()> + '_>>;
+
+    /// Number of frames (samples per channel) forwarded so far.
+    fn queued_frames(&self) -> u64;
+}
+
+/// Sample types ffmpeg can decode to.
+trait FFmpegSample: Pod {
+    /// The packed ffmpeg sample format of the type.
+    const FORMAT: ffmpeg_next::util::format::sample::Sample;
+}
+
+macro_rules! ffmpeg_sample {
+    ($sample:ty, $format:ident) => {
+        impl FFmpegSample for $sample {
+            const FORMAT: ffmpeg_next::util::format::sample::Sample =
+                ffmpeg_next::util::format::sample::Sample::$format(
+                    ffmpeg_next::util::format::sample::Type::Packed,
+                );
+        }
+    };
+}
+
+ffmpeg_sample!(u8, U8);
+ffmpeg_sample!(i16, I16);
+ffmpeg_sample!(i32, I32);
+ffmpeg_sample!(i64, I64);
+ffmpeg_sample!(f32, F32);
+ffmpeg_sample!(f64, F64);
+
+/// Finds the output device and its configuration, `None` for the silent output.
+fn open_output_device(
+    output_device: &OutputDevice,
+) -> Result<Option<(cpal::Device, cpal::SupportedStreamConfig)>, anyhow::Error> {
+    let host = cpal::default_host();
+    let device = match output_device {
+        OutputDevice::Silent => return Ok(None),
+        OutputDevice::Default => host.default_output_device(),
+        OutputDevice::Named(name) => host
+            .output_devices()?
+            .find(|device| device.name().is_ok_and(|device_name| device_name == *name)),
+    };
+    let device = device.ok_or_else(|| anyhow::anyhow!("no audio output device {:?} available", output_device))?;
+
+    let config = device.default_output_config()?;
+    if !is_supported_sample_format(config.sample_format()) {
+        return Err(anyhow::anyhow!("unsupported audio output format {}", config.sample_format()));
+    }
+    Ok(Some((device, config)))
 }
 
-impl<T: Pod, R: RbRef> FFMpegToCPalSampleForwarder for ringbuf::Producer<T, R>
-where
-    <R as RbRef>::Rb: RbWrite<T>,
-{
+/// Whether `device` can play a stream of `config`, which may not be its default.
+fn supports_config(device: &cpal::Device, config: &cpal::SupportedStreamConfig) -> bool {
+    device.supported_output_configs().is_ok_and(|mut supported_configs| {
+        supported_configs.any(|supported| {
+            supported.channels() == config.channels()
+                && supported.sample_format() == config.sample_format()
+                && (supported.min_sample_rate()..=supported.max_sample_rate()).contains(&config.sample_rate())
+        })
+    })
+}
+
+fn silent_output_config() -> cpal::SupportedStreamConfig {
+    cpal::SupportedStreamConfig::new(
+        SILENT_OUTPUT_CHANNELS,
+        cpal::SampleRate(SILENT_OUTPUT_SAMPLE_RATE),
+        cpal::SupportedBufferSize::Unknown,
+        cpal::SampleFormat::F32,
+    )
+}
+
+/// Whether [`AudioPlaybackThread`] can play to a cpal stream with samples of `format`.
+fn is_supported_sample_format(format: cpal::SampleFormat) -> bool {
+    matches!(
+        format,
+        cpal::SampleFormat::I8
+            | cpal::SampleFormat::I16
+            | cpal::SampleFormat::I32
+            | cpal::SampleFormat::I64
+            | cpal::SampleFormat::U8
+            | cpal::SampleFormat::U16
+            | cpal::SampleFormat::U32
+            | cpal::SampleFormat::U64
+            | cpal::SampleFormat::F32
+            | cpal::SampleFormat::F64
+    )
+}
+
+/// Buffers samples decoded as `S` for a cpal stream of `T` samples, converting them if the
+/// types differ.
+struct SampleProducer<T, S> {
+    producer: HeapProducer<T>,
+    /// The decoded channel for each channel of the device, if it takes them in another order.
+    channel_order: Option<&'static [usize]>,
+    queued_frames: u64,
+    _decoded_sample: PhantomData<S>,
+}
+
+impl<T: SizedSample + FromSample<S>, S: FFmpegSample> FFMpegToCPalSampleForwarder for SampleProducer<T, S> {
     fn forward(
         &mut self,
         audio_frame: ffmpeg_next::frame::Audio,
@@ -145,90 +452,618 @@ where
         Box::pin(async move {
             // Audio::plane() returns the wrong slice size, so correct it by hand. See also
             // for a fix https://github.com/zmwangx/rust-ffmpeg/pull/104.
-            let expected_bytes =
-                audio_frame.samples() * audio_frame.channels() as usize * core::mem::size_of::<T>();
-            let cpal_sample_data: &[T] =
+            let channels = audio_frame.channels() as usize;
+            let expected_bytes = audio_frame.samples() * channels * core::mem::size_of::<S>();
+            let mut decoded_samples: &[S] =
                 bytemuck::cast_slice(&audio_frame.data(0)[..expected_bytes]);
 
-            while self.free_len() < cpal_sample_data.len() {
-                smol::Timer::after(std::time::Duration::from_millis(16)).await;
+            // Buffer the samples for playback, in parts if the frame is larger than the buffer.
+            // Only whole samples of all channels are pushed, so the output never gets out of
+            // step with the channel order.
+            while !decoded_samples.is_empty() {
+                let free = self.producer.free_len() / channels * channels;
+                if free == 0 {
+                    smol::Timer::after(std::time::Duration::from_millis(16)).await;
+                    continue;
+                }
+                let part = &decoded_samples[..free.min(decoded_samples.len())];
+                let pushed = match self.channel_order {
+                    Some(channel_order) => {
+                        let mut samples = part.chunks_exact(channels).flat_map(|group| {
+                            channel_order.iter().map(move |channel| T::from_sample(group[*channel]))
+                        });
+                        self.producer.push_iter(&mut samples)
+                    }
+                    None => self.producer.push_iter(&mut part.iter().map(|sample| T::from_sample(*sample))),
+                };
+                decoded_samples = &decoded_samples[pushed..];
+                self.queued_frames += (pushed / channels) as u64;
             }
-
-            // Buffer the samples for playback
-            self.push_slice(cpal_sample_data);
         })
     }
+
+    fn queued_frames(&self) -> u64 {
+        self.queued_frames
+    }
+}
+
+/// The stream samples are played on. Dropping it stops playback.
+enum OutputStream {
+    Device(cpal::Stream),
+    /// Consumes samples in real time without playing them, so that the playback clock keeps
+    /// running without an output device.
+    Silent { stop: Arc<AtomicBool>, thread: Option<std::thread::JoinHandle<()>> },
+}
+
+impl OutputStream {
+    /// Plays on `device`. `data_callback` is shared so that it can still be handed to the silent
+    /// output if the device refuses the stream.
+    fn device<T: SizedSample + Send + 'static>(
+        device: &cpal::Device,
+        config: &cpal::SupportedStreamConfig,
+        data_callback: Arc<Mutex<impl FnMut(&mut [T]) + Send + 'static>>,
+    ) -> Result<Self, anyhow::Error> {
+        let cpal_stream = device.build_output_stream(
+            &config.config(),
+            move |data: &mut [T], _| (*data_callback.lock().unwrap())(data),
+            move |err| {
+                eprintln!("error feeding audio stream to cpal: {}", err);
+            },
+            None,
+        )?;
+        cpal_stream.play()?;
+        Ok(OutputStream::Device(cpal_stream))
+    }
+
+    fn silent<T: SizedSample + Send + 'static>(
+        config: &cpal::SupportedStreamConfig,
+        mut data_callback: impl FnMut(&mut [T]) + Send + 'static,
+    ) -> Result<Self, std::io::Error> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let samples_per_period = (config.sample_rate().0 as f64 * SILENT_OUTPUT_PERIOD.as_secs_f64())
+            as usize
+            * config.channels() as usize;
+
+        let thread = std::thread::Builder::new().name("silent audio output".into()).spawn({
+            let stop = stop.clone();
+            move || {
+                let mut data = vec![T::EQUILIBRIUM; samples_per_period];
+                let mut next_period = std::time::Instant::now();
+                while !stop.load(Ordering::SeqCst) {
+                    data_callback(&mut data);
+                    next_period += SILENT_OUTPUT_PERIOD;
+                    std::thread::sleep(next_period.saturating_duration_since(std::time::Instant::now()));
+                }
+            }
+        })?;
+
+        Ok(OutputStream::Silent { stop, thread: Some(thread) })
+    }
 }
 
+impl Drop for OutputStream {
+    fn drop(&mut self) {
+        if let OutputStream::Silent { stop, thread } = self {
+            stop.store(true, Ordering::SeqCst);
+            if let Some(thread) = thread.take() {
+                thread.join().unwrap();
+            }
+        }
+    }
+}
+
+/// The format a [`FFmpegToCPalForwarder`] plays at.
+struct OutputConfig {
+    config: cpal::SupportedStreamConfig,
+    /// The ffmpeg layout of the device's channels.
+    channel_layout: ffmpeg_next::util::channel_layout::ChannelLayout,
+    /// The decoded channel for each channel of the device, if it takes them in another order.
+    channel_order: Option<&'static [usize]>,
+}
+
+/// The state a [`FFmpegToCPalForwarder`] shares with the demuxer and the player.
+struct SharedState {
+    flushing: Arc<AtomicBool>,
+    output_switch: OutputSwitch,
+    clock: Arc<PlaybackClock>,
+    settings: Arc<AudioSettings>,
+}
+
+/// Opens an output stream on the device, or silently without one.
+type OpenOutputStream = Box<dyn Fn(Option<&cpal::Device>) -> Result<OutputStream, anyhow::Error>>;
+
 struct FFmpegToCPalForwarder {
-    _cpal_stream: cpal::Stream,
+    /// `None` only while switching devices, so that the previous stream is closed first.
+    _output_stream: Option<OutputStream>,
+    open_output_stream: OpenOutputStream,
+    output_switch: OutputSwitch,
     ffmpeg_to_cpal_pipe: Box<dyn FFMpegToCPalSampleForwarder>,
-    packet_receiver: smol::channel::Receiver<ffmpeg_next::codec::packet::packet::Packet>,
+    packet_receiver: smol::channel::Receiver<PacketMessage>,
     packet_decoder: ffmpeg_next::decoder::Audio,
     resampler: ffmpeg_next::software::resampling::Context,
+    time_base_seconds: f64,
+    flushing: Arc<AtomicBool>,
+    /// Tells the cpal callback to drop everything still buffered in the ring buffer.
+    discard_buffered: Arc<AtomicBool>,
+    clock: Arc<PlaybackClock>,
+    output_format: ffmpeg_next::util::format::sample::Sample,
+    output_channel_layout: ffmpeg_next::util::channel_layout::ChannelLayout,
+    output_sample_rate: u32,
+    /// The `loudnorm` stage, kept across rate changes, as it holds back the seconds it looks
+    /// ahead.
+    normalizer: Option<AudioFilter>,
+    /// The `atempo` stage, only used away from normal speed.
+    tempo_filter: Option<AudioFilter>,
+    /// The rate the audio is currently played at, `None` until it is set up after a seek.
+    tempo_rate: Option<f64>,
+    /// [`FFMpegToCPalSampleForwarder::queued_frames`] when the clock was last resumed, the
+    /// clock counts the frames played from there.
+    queued_frames_at_resume: u64,
+    settings: Arc<AudioSettings>,
+    /// Whether the resampler was set up with dialogue boost.
+    resampler_dialogue_boost: bool,
 }
 
 impl FFmpegToCPalForwarder {
-    fn new<T: Send + Pod + SizedSample + 'static>(
-        config: cpal::SupportedStreamConfig,
-        device: &cpal::Device,
-        packet_receiver: smol::channel::Receiver<ffmpeg_next::codec::packet::packet::Packet>,
+    /// Plays to a cpal stream of `T` samples on `device`, or silently without one, decoding to
+    /// `S` samples.
+    fn new<T: Send + SizedSample + FromSample<S> + 'static, S: FFmpegSample>(
+        output: OutputConfig,
+        device: Option<&cpal::Device>,
+        packet_receiver: smol::channel::Receiver<PacketMessage>,
         packet_decoder: ffmpeg_next::decoder::Audio,
-        output_format: ffmpeg_next::util::format::sample::Sample,
-        output_channel_layout: ffmpeg_next::util::channel_layout::ChannelLayout,
-    ) -> Self {
-        let buffer = HeapRb::new(4096);
+        time_base_seconds: f64,
+        shared: SharedState,
+    ) -> Result<Self, anyhow::Error> {
+        let OutputConfig { config, channel_layout: output_channel_layout, channel_order } = output;
+        let SharedState { flushing, output_switch, clock, settings } = shared;
+        let output_format = S::FORMAT;
+        let buffer = HeapRb::new(OUTPUT_BUFFER_FRAMES * config.channels() as usize);
         let (sample_producer, mut sample_consumer) = buffer.split();
 
-        let cpal_stream = device
-            .build_output_stream(
-                &config.config(),
-                move |data, _| {
-                    let filled = sample_consumer.pop_slice(data);
-                    data[filled..].fill(T::EQUILIBRIUM);
-                },
-                move |err| {
-                    eprintln!("error feeding audio stream to cpal: {}", err);
-                },
-                None,
-            )
-            .unwrap();
-
-        cpal_stream.play().unwrap();
-
-        let resampler = ffmpeg_next::software::resampling::Context::get(
-            packet_decoder.format(),
-            packet_decoder.channel_layout(),
-            packet_decoder.rate(),
+        let discard_buffered = Arc::new(AtomicBool::new(false));
+
+        let data_callback = {
+            let discard_buffered = discard_buffered.clone();
+            let clock = clock.clone();
+            let settings = settings.clone();
+            let channels = config.channels() as usize;
+            move |data: &mut [T]| {
+                // Cleared before the flag is reset, as the forwarder counts the samples it
+                // buffers from then on towards the playback clock
+                if discard_buffered.load(Ordering::SeqCst) {
+                    sample_consumer.clear();
+                    discard_buffered.store(false, Ordering::SeqCst);
+                }
+                if clock.is_paused() {
+                    // Keep the buffered samples for when playback resumes
+                    data.fill(T::EQUILIBRIUM);
+                    return;
+                }
+                let filled = sample_consumer.pop_slice(data);
+                data[filled..].fill(T::EQUILIBRIUM);
+
+                // Applied here rather than when decoding, so that changes are heard right away
+                let volume = if settings.muted() { 0. } else { settings.volume() };
+                if volume != 1. {
+                    let amplitude = <T::Float as FromSample<f32>>::from_sample_(volume);
+                    for sample in &mut data[..filled] {
+                        *sample = sample.mul_amp(amplitude);
+                    }
+                }
+
+                // Only samples that were actually played advance the clock, so it stops
+                // on underruns.
+                clock.advance_audio((filled / channels) as u64);
+            }
+        };
+
+        // The callback is shared by the streams of every device played on, so that switching
+        // devices keeps the buffered samples
+        let data_callback = Arc::new(Mutex::new(data_callback));
+        let open_output_stream: OpenOutputStream = {
+            let config = config.clone();
+            Box::new(move |device: Option<&cpal::Device>| {
+                // A device that is busy or rejects the configuration is played to silently, like
+                // a missing one
+                if let Some(device) = device {
+                    match OutputStream::device(device, &config, data_callback.clone()) {
+                        Ok(output_stream) => return Ok(output_stream),
+                        Err(err) => eprintln!("error opening audio stream: {}, playing without sound", err),
+                    }
+                }
+                let data_callback = data_callback.clone();
+                let silent_callback = move |data: &mut [T]| (*data_callback.lock().unwrap())(data);
+                Ok(OutputStream::silent(&config, silent_callback)?)
+            })
+        };
+        let output_stream = open_output_stream(device)?;
+
+        let resampler_dialogue_boost = settings.dialogue_boost();
+        let resampler = create_resampler(
+            &packet_decoder,
             output_format,
             output_channel_layout,
             config.sample_rate().0,
-        )
-        .unwrap();
+            resampler_dialogue_boost,
+        )?;
 
-        Self {
-            _cpal_stream: cpal_stream,
-            ffmpeg_to_cpal_pipe: Box::new(sample_producer),
+        Ok(Self {
+            _output_stream: Some(output_stream),
+            open_output_stream,
+            output_switch,
+            ffmpeg_to_cpal_pipe: Box::new(SampleProducer::<T, S> {
+                producer: sample_producer,
+                channel_order,
+                queued_frames: 0,
+                _decoded_sample: PhantomData,
+            }),
             packet_receiver,
             packet_decoder,
             resampler,
-        }
+            time_base_seconds,
+            flushing,
+            discard_buffered,
+            clock,
+            output_format,
+            output_channel_layout,
+            output_sample_rate: config.sample_rate().0,
+            normalizer: None,
+            tempo_filter: None,
+            tempo_rate: None,
+            queued_frames_at_resume: 0,
+            settings,
+            resampler_dialogue_boost,
+        })
     }
 
     async fn stream(&mut self) {
+        // Set after a seek: frames ending before this position are dropped.
+        let mut seek_position: Option<f64> = None;
+        // Whether the playback clock has to be resumed from the next forwarded frame.
+        let mut resume_clock = true;
+
         loop {
-            let Ok(packet) = self.packet_receiver.recv().await else { break };
+            let Ok(message) = self.packet_receiver.recv().await else { break };
+
+            let output_switch = self.output_switch.lock().unwrap().take();
+            if let Some(device) = output_switch {
+                self._output_stream = None;
+                self._output_stream = (self.open_output_stream)(device.as_ref())
+                    .map_err(|err| eprintln!("error switching the audio output: {}", err))
+                    .ok();
+            }
+
+            let packet = match message {
+                PacketMessage::Packet(packet) => packet,
+                PacketMessage::Flush(position) => {
+                    self.packet_decoder.flush();
+                    self.discard_buffered.store(true, Ordering::SeqCst);
+                    // The filters still hold audio from before the seek. A fresh normalizer has to
+                    // look ahead again before it plays anything, which holds the clock until
+                    // that much audio is decoded.
+                    if self.normalizer.is_some() {
+                        self.normalizer = self.create_normalizer();
+                    }
+                    self.tempo_filter = None;
+                    self.tempo_rate = None;
+                    seek_position = Some(position);
+                    resume_clock = true;
+                    self.flushing.store(false, Ordering::SeqCst);
+                    continue;
+                }
+            };
 
             self.packet_decoder.send_packet(&packet).unwrap();
 
             let mut decoded_frame = ffmpeg_next::util::frame::Audio::empty();
 
             while self.packet_decoder.receive_frame(&mut decoded_frame).is_ok() {
+                if self.flushing.load(Ordering::SeqCst) {
+                    continue;
+                }
+
+                if let Some(position) = seek_position {
+                    if let Some(pts) = decoded_frame.pts() {
+                        let frame_end = pts as f64 * self.time_base_seconds
+                            + decoded_frame.samples() as f64 / decoded_frame.rate() as f64;
+                        if frame_end < position {
+                            continue;
+                        }
+                    }
+                    seek_position = None;
+                }
+
+                let dialogue_boost = self.settings.dialogue_boost();
+                if dialogue_boost != self.resampler_dialogue_boost {
+                    match create_resampler(
+                        &self.packet_decoder,
+                        self.output_format,
+                        self.output_channel_layout,
+                        self.output_sample_rate,
+                        dialogue_boost,
+                    ) {
+                        Ok(resampler) => self.resampler = resampler,
+                        Err(err) => eprintln!("error setting up the downmix: {}", err),
+                    }
+                    self.resampler_dialogue_boost = dialogue_boost;
+                }
+
                 let mut resampled_frame = ffmpeg_next::util::frame::Audio::empty();
                 self.resampler.run(&decoded_frame, &mut resampled_frame).unwrap();
+                // The filters and the clock count in output samples
+                resampled_frame.set_pts(decoded_frame.pts().map(|pts| {
+                    (pts as f64 * self.time_base_seconds * self.output_sample_rate as f64).round() as i64
+                }));
+
+                if resume_clock {
+                    // Wait for the cpal callback to drop the samples buffered before the seek,
+                    // so that the clock starts counting with this frame.
+                    while self.discard_buffered.load(Ordering::SeqCst) {
+                        smol::Timer::after(std::time::Duration::from_millis(1)).await;
+                    }
+                    if let Some(pts) = decoded_frame.pts() {
+                        self.clock
+                            .resume_with_audio(pts as f64 * self.time_base_seconds, self.output_sample_rate);
+                        self.queued_frames_at_resume = self.ffmpeg_to_cpal_pipe.queued_frames();
+                        resume_clock = false;
+                    }
+                }
+
+                if self.settings.loudness_normalization() != self.normalizer.is_some() {
+                    match self.normalizer.take() {
+                        // Play what it still holds back, so that no audio is skipped
+                        Some(mut normalizer) => {
+                            if normalizer.flush().is_ok() {
+                                while let Some(normalized_frame) = normalizer.pull() {
+                                    self.play(normalized_frame).await;
+                                }
+                            }
+                        }
+                        None => self.normalizer = self.create_normalizer(),
+                    }
+                }
 
-                self.ffmpeg_to_cpal_pipe.forward(resampled_frame).await;
+                match &mut self.normalizer {
+                    Some(normalizer) => {
+                        if let Err(err) = normalizer.push(&resampled_frame) {
+                            eprintln!("error normalizing audio: {}", err);
+                            continue;
+                        }
+                        let normalized_frames: Vec<_> = std::iter::from_fn(|| normalizer.pull()).collect();
+                        for normalized_frame in normalized_frames {
+                            self.play(normalized_frame).await;
+                        }
+                    }
+                    // Play unnormalized rather than not at all
+                    None => self.play(resampled_frame).await,
+                }
             }
         }
     }
+
+    fn create_normalizer(&self) -> Option<AudioFilter> {
+        AudioFilter::loudness_normalization(self.output_format, self.output_channel_layout, self.output_sample_rate)
+            .map_err(|err| eprintln!("error creating loudness normalization: {}", err))
+            .ok()
+    }
+
+    /// Time-stretches the frame to the playback rate and hands it to the output.
+    async fn play(&mut self, frame: ffmpeg_next::util::frame::Audio) {
+        let rate = self.clock.rate();
+        if self.tempo_rate != Some(rate) {
+            // Play what the previous filter still holds, so that no audio is skipped
+            if let Some(mut tempo_filter) = self.tempo_filter.take() {
+                if tempo_filter.flush().is_ok() {
+                    while let Some(filtered_frame) = tempo_filter.pull() {
+                        self.ffmpeg_to_cpal_pipe.forward(filtered_frame).await;
+                    }
+                }
+            }
+            self.tempo_filter = (rate != 1.0)
+                .then(|| {
+                    AudioFilter::tempo(rate, self.output_format, self.output_channel_layout, self.output_sample_rate)
+                        .map_err(|err| eprintln!("error creating audio filter: {}", err))
+                        .ok()
+                })
+                .flatten();
+            // Without the filter, the audio plays at normal speed and the clock follows it
+            let played_rate = if self.tempo_filter.is_some() { rate } else { 1.0 };
+            self.tempo_rate = Some(rate);
+
+            // The samples buffered so far were produced at the previous rate, the clock only
+            // switches over once they are played, starting at the position of this frame.
+            self.clock.change_audio_rate_at(
+                self.ffmpeg_to_cpal_pipe.queued_frames() - self.queued_frames_at_resume,
+                frame.pts().map(|pts| pts as f64 / self.output_sample_rate as f64),
+                played_rate,
+            );
+        }
+
+        match &mut self.tempo_filter {
+            Some(tempo_filter) => {
+                if let Err(err) = tempo_filter.push(&frame) {
+                    eprintln!("error filtering audio: {}", err);
+                    return;
+                }
+                while let Some(filtered_frame) = tempo_filter.pull() {
+                    self.ffmpeg_to_cpal_pipe.forward(filtered_frame).await;
+                }
+            }
+            None => self.ffmpeg_to_cpal_pipe.forward(frame).await,
+        }
+    }
+}
+
+/// The channels of ffmpeg's 5.1 (back) and 7.1 layouts in the order of ALSA devices.
+const ALSA_5POINT1_ORDER: &[usize] = &[0, 1, 4, 5, 2, 3];
+const ALSA_7POINT1_ORDER: &[usize] = &[0, 1, 4, 5, 2, 3, 6, 7];
+
+/// The ffmpeg channel layout matching an output device with `channels` channels on `host`, and
+/// for devices that take the channels in another order than ffmpeg, which channel of the layout
+/// goes to each channel of the device.
+///
+/// ffmpeg orders the channels like WAVE files (front left, front right, center, LFE, surrounds),
+/// as do WASAPI and CoreAudio. ALSA, which PulseAudio and PipeWire are usually reached through as
+/// well, puts the rear pair before center and LFE (FL FR RL RR FC LFE for 5.1). Other channel
+/// counts are assumed to be in ffmpeg's default order.
+fn output_channels(
+    channels: u16,
+    host: cpal::HostId,
+) -> (ffmpeg_next::util::channel_layout::ChannelLayout, Option<&'static [usize]>) {
+    use ffmpeg_next::util::channel_layout::ChannelLayout;
+
+    let alsa_order = host.name() == "ALSA";
+    match (channels, alsa_order) {
+        (1, _) => (ChannelLayout::MONO, None),
+        (2, _) => (ChannelLayout::STEREO, None),
+        (6, false) => (ChannelLayout::_5POINT1, None),
+        // ALSA calls the surround pair of 5.1 rear speakers
+        (6, true) => (ChannelLayout::_5POINT1_BACK, Some(ALSA_5POINT1_ORDER)),
+        (8, false) => (ChannelLayout::_7POINT1, None),
+        (8, true) => (ChannelLayout::_7POINT1, Some(ALSA_7POINT1_ORDER)),
+        (channels, _) => (ChannelLayout::default(channels as i32), None),
+    }
+}
+
+/// Converts decoded audio to the output format, downmixing or upmixing to the output layout.
+fn create_resampler(
+    decoder: &ffmpeg_next::decoder::Audio,
+    output_format: ffmpeg_next::util::format::sample::Sample,
+    output_channel_layout: ffmpeg_next::util::channel_layout::ChannelLayout,
+    output_sample_rate: u32,
+    dialogue_boost: bool,
+) -> Result<ffmpeg_next::software::resampling::Context, ffmpeg_next::Error> {
+    // Some codecs only report a channel count, without this the channels can't be mixed properly
+    let input_channel_layout = match decoder.channel_layout() {
+        layout if layout.is_empty() => {
+            ffmpeg_next::util::channel_layout::ChannelLayout::default(decoder.channels() as i32)
+        }
+        layout => layout,
+    };
+
+    let mut resampler = ffmpeg_next::software::resampling::Context::get(
+        decoder.format(),
+        input_channel_layout,
+        decoder.rate(),
+        output_format,
+        output_channel_layout,
+        output_sample_rate,
+    )?;
+
+    if dialogue_boost {
+        // The mix levels only take effect on initialization, so initialize again with them
+        unsafe {
+            let context = resampler.as_mut_ptr();
+            for (option, value) in [
+                (c"center_mix_level", DIALOGUE_BOOST_CENTER_MIX_LEVEL),
+                (c"surround_mix_level", DIALOGUE_BOOST_SURROUND_MIX_LEVEL),
+                // Louder center channels must not clip
+                (c"rematrix_maxval", 1.0),
+            ] {
+                let result = ffmpeg_next::ffi::av_opt_set_double(context.cast(), option.as_ptr(), value, 0);
+                if result < 0 {
+                    return Err(ffmpeg_next::Error::from(result));
+                }
+            }
+            let result = ffmpeg_next::ffi::swr_init(context);
+            if result < 0 {
+                return Err(ffmpeg_next::Error::from(result));
+            }
+        }
+    }
+
+    Ok(resampler)
+}
+
+/// Filters the audio with an ffmpeg filter graph: EBU R128 loudness normalization with
+/// `loudnorm`, or time-stretching with `atempo`, so that speech keeps its natural pitch at
+/// playback rates other than 1.0.
+struct AudioFilter {
+    graph: ffmpeg_next::filter::Graph,
+}
+
+impl AudioFilter {
+    /// Normalizes the loudness. In its dynamic mode, `loudnorm` looks about 3 s ahead and only
+    /// starts putting out audio once it has seen that much.
+    fn loudness_normalization(
+        format: ffmpeg_next::util::format::sample::Sample,
+        channel_layout: ffmpeg_next::util::channel_layout::ChannelLayout,
+        sample_rate: u32,
+    ) -> Result<Self, ffmpeg_next::Error> {
+        let filters = format!(
+            // loudnorm works at 192 kHz
+            "loudnorm=I={}:LRA={}:TP={},aresample={}",
+            LOUDNESS_TARGET, LOUDNESS_RANGE_TARGET, LOUDNESS_TRUE_PEAK, sample_rate
+        );
+        Self::new(&filters, format, channel_layout, sample_rate)
+    }
+
+    /// Plays the audio at `rate` times the speed, keeping its pitch.
+    fn tempo(
+        rate: f64,
+        format: ffmpeg_next::util::format::sample::Sample,
+        channel_layout: ffmpeg_next::util::channel_layout::ChannelLayout,
+        sample_rate: u32,
+    ) -> Result<Self, ffmpeg_next::Error> {
+        // A single atempo instance only covers 0.5 to 2.0 on older ffmpeg versions, so chain
+        // several of them for rates outside of that.
+        let mut filters = Vec::new();
+        let mut remaining_rate = rate;
+        while remaining_rate > 2.0 {
+            filters.push(String::from("atempo=2.0"));
+            remaining_rate /= 2.0;
+        }
+        while remaining_rate < 0.5 {
+            filters.push(String::from("atempo=0.5"));
+            remaining_rate /= 0.5;
+        }
+        filters.push(format!("atempo={}", remaining_rate));
+
+        Self::new(&filters.join(","), format, channel_layout, sample_rate)
+    }
+
+    fn new(
+        filters: &str,
+        format: ffmpeg_next::util::format::sample::Sample,
+        channel_layout: ffmpeg_next::util::channel_layout::ChannelLayout,
+        sample_rate: u32,
+    ) -> Result<Self, ffmpeg_next::Error> {
+        let mut graph = ffmpeg_next::filter::Graph::new();
+
+        let args = format!(
+            "time_base=1/{sample_rate}:sample_rate={sample_rate}:sample_fmt={}:channel_layout=0x{:x}",
+            format.name(),
+            channel_layout.bits()
+        );
+        graph.add(&ffmpeg_next::filter::find("abuffer").unwrap(), "in", &args)?;
+        graph.add(&ffmpeg_next::filter::find("abuffersink").unwrap(), "out", "")?;
+
+        {
+            // Make sure the output matches what the cpal stream expects
+            let mut out = graph.get("out").unwrap();
+            out.set_sample_format(format);
+            out.set_channel_layout(channel_layout);
+            out.set_sample_rate(sample_rate);
+        }
+
+        graph.output("in", 0)?.input("out", 0)?.parse(filters)?;
+        graph.validate()?;
+
+        Ok(Self { graph })
+    }
+
+    fn push(&mut self, frame: &ffmpeg_next::util::frame::Audio) -> Result<(), ffmpeg_next::Error> {
+        self.graph.get("in").unwrap().source().add(frame)
+    }
+
+    /// Ends the input, so that [`Self::pull`] also returns what the filters still hold back.
+    fn flush(&mut self) -> Result<(), ffmpeg_next::Error> {
+        self.graph.get("in").unwrap().source().flush()
+    }
+
+    fn pull(&mut self) -> Option<ffmpeg_next::util::frame::Audio> {
+        let mut frame = ffmpeg_next::util::frame::Audio::empty();
+        self.graph.get("out").unwrap().sink().frame(&mut frame).ok().map(|_| frame)
+    }
 }
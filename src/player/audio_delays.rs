@@ -0,0 +1,128 @@
+// Copyright © SixtyFPS GmbH <info@slint.dev>
+// SPDX-License-Identifier: MIT
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::MAX_AUDIO_DELAY_MS;
+
+/// Name of the file in the configuration directory that keeps the audio delays.
+const AUDIO_DELAYS_FILE_NAME: &str = "audio_delays.txt";
+
+/// The audio delays chosen for individual files, so that a file that needed one plays in sync
+/// the next time it is opened.
+///
+/// They are stored in the user's configuration directory, one `<milliseconds>\t<path>` line per
+/// file. Files without a delay aren't listed.
+pub struct AudioDelays {
+    file: Option<PathBuf>,
+    delays: HashMap<PathBuf, i32>,
+}
+
+impl AudioDelays {
+    /// Reads the stored delays. A missing or unreadable file starts out empty, lines that can't be
+    /// parsed are skipped.
+    pub fn load() -> Self {
+        Self::from_file(config_directory().map(|directory| directory.join(AUDIO_DELAYS_FILE_NAME)))
+    }
+
+    fn from_file(file: Option<PathBuf>) -> Self {
+        let delays = file
+            .as_ref()
+            .and_then(|file| std::fs::read_to_string(file).ok())
+            .map(|contents| {
+                contents
+                    .lines()
+                    .filter_map(|line| {
+                        let (delay, path) = line.split_once('\t')?;
+                        Some((PathBuf::from(path), delay.trim().parse().ok()?))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self { file, delays }
+    }
+
+    /// The delay in milliseconds stored for `video_path`, 0 if there is none. Delays beyond
+    /// [`MAX_AUDIO_DELAY_MS`] are limited to it.
+    pub fn get(&self, video_path: &Path) -> i32 {
+        self.delays
+            .get(&delay_key(video_path))
+            .map_or(0, |delay| (*delay).clamp(-MAX_AUDIO_DELAY_MS, MAX_AUDIO_DELAY_MS))
+    }
+
+    /// Stores the delay in milliseconds for `video_path` and writes all delays back.
+    pub fn set(&mut self, video_path: &Path, delay: i32) -> Result<(), anyhow::Error> {
+        let key = delay_key(video_path);
+        if delay == 0 {
+            self.delays.remove(&key);
+        } else {
+            self.delays.insert(key, delay);
+        }
+
+        let file = self
+            .file
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("no configuration directory to store audio delays in"))?;
+        if let Some(directory) = file.parent() {
+            std::fs::create_dir_all(directory)?;
+        }
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(file)?);
+        for (path, delay) in &self.delays {
+            writeln!(writer, "{}\t{}", delay, path.display())?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Files are told apart by their absolute path, so that relative paths opened from another
+/// directory don't mix them up.
+fn delay_key(video_path: &Path) -> PathBuf {
+    video_path.canonicalize().unwrap_or_else(|_| video_path.to_path_buf())
+}
+
+/// `$XDG_CONFIG_HOME/sentoani`, falling back to `~/.config/sentoani`, or `%APPDATA%\sentoani` on
+/// Windows.
+fn config_directory() -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|directory| !directory.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    }?;
+    Some(base.join("sentoani"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_delays_per_file_and_limits_them() {
+        let directory = std::env::temp_dir().join(format!("sentoani-audio-delays-{}", std::process::id()));
+        let file = directory.join(AUDIO_DELAYS_FILE_NAME);
+        let video = directory.join("Show_1.mkv");
+        let other_video = directory.join("Show_2.mkv");
+
+        let mut audio_delays = AudioDelays::from_file(Some(file.clone()));
+        audio_delays.set(&video, -250).unwrap();
+        audio_delays.set(&other_video, 400).unwrap();
+        audio_delays.set(&other_video, 0).unwrap();
+
+        let audio_delays = AudioDelays::from_file(Some(file.clone()));
+        assert_eq!(audio_delays.get(&video), -250);
+        assert_eq!(audio_delays.get(&other_video), 0);
+
+        // Delays stored with a larger limit, and lines that can't be parsed
+        std::fs::write(&file, format!("5000\t{}\nnot a delay\t{}\n", video.display(), other_video.display()))
+            .unwrap();
+        let audio_delays = AudioDelays::from_file(Some(file));
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(audio_delays.get(&video), MAX_AUDIO_DELAY_MS);
+        assert_eq!(audio_delays.get(&other_video), 0);
+    }
+}
@@ -0,0 +1,298 @@
+// Copyright © SixtyFPS GmbH <info@slint.dev>
+// SPDX-License-Identifier: MIT
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// The master clock the playback threads present against.
+///
+/// The clock advances with the number of samples the audio output device has actually consumed,
+/// so video follows the audio instead of drifting away from it. After a seek it holds still until
+/// the audio thread resumes it, and while paused the output stops consuming samples, so the
+/// clock continues exactly where it stopped. Files without audio run on the wall clock instead.
+///
+/// Rate changes only reach the audio once the samples buffered at the old rate are played, so
+/// the audio thread tells the clock where the first samples at the new rate are, and the clock
+/// switches over there, re-anchored at the position of those samples.
+///
+/// While following the audio, and while held for it after a seek, the position is shifted by the
+/// audio delay, so that video and subtitles of files with out of sync audio are presented that
+/// much ahead of the samples heard.
+pub struct PlaybackClock {
+    /// Audio frames (samples per channel) consumed by the output device since the clock was
+    /// resumed with the audio.
+    frames_played: AtomicU64,
+    paused: AtomicBool,
+    state: Mutex<ClockState>,
+}
+
+struct ClockState {
+    /// The media position (in seconds) at the time the clock was last rebased.
+    base_position: f64,
+    /// The played audio frames the clock was last rebased at.
+    base_frames: u64,
+    /// Media seconds per second of output, as requested. The audio follows with a delay.
+    rate: f64,
+    /// Seconds the audio is played later than the video, negative to play it earlier.
+    audio_delay: f64,
+    source: ClockSource,
+}
+
+enum ClockSource {
+    Held,
+    /// `rate` is the rate the samples being played were produced at, `rate_changes` are the
+    /// switches to other rates still buffered, in the order they are played.
+    Audio { sample_rate: u32, rate: f64, rate_changes: VecDeque<AudioRateChange> },
+    /// Used when there is no audio track, `since` is when the clock was last rebased.
+    Wall { since: Instant },
+}
+
+/// Where the audio output reaches samples produced at another rate.
+struct AudioRateChange {
+    /// Played audio frames at which the samples start.
+    frames: u64,
+    /// The media position (in seconds) of the first of the samples, if known.
+    position: Option<f64>,
+    rate: f64,
+}
+
+impl PlaybackClock {
+    pub fn new() -> Self {
+        Self {
+            frames_played: AtomicU64::new(0),
+            paused: AtomicBool::new(false),
+            state: Mutex::new(ClockState {
+                base_position: 0.,
+                base_frames: 0,
+                rate: 1.,
+                audio_delay: 0.,
+                source: ClockSource::Held,
+            }),
+        }
+    }
+
+    /// The current playback position in seconds, the position of the video frame that is due.
+    pub fn position(&self) -> f64 {
+        let mut state = self.state.lock().unwrap();
+        // Without audio there is nothing to be out of sync with
+        let audio_delay = match state.source {
+            ClockSource::Wall { .. } => 0.,
+            _ => state.audio_delay,
+        };
+        let frames = self.frames_played.load(Ordering::SeqCst);
+        self.apply_rate_changes(&mut state, frames);
+        state.base_position + self.elapsed(&state, frames) + audio_delay
+    }
+
+    /// The position in seconds of the audio being heard, without the audio delay. Seeks and
+    /// track switches start from here, as the audio is what resumes the clock.
+    pub fn audio_position(&self) -> f64 {
+        let mut state = self.state.lock().unwrap();
+        let frames = self.frames_played.load(Ordering::SeqCst);
+        self.apply_rate_changes(&mut state, frames);
+        state.base_position + self.elapsed(&state, frames)
+    }
+
+    /// Media seconds covered since the last rebase, `frames` being the played audio frames.
+    fn elapsed(&self, state: &ClockState, frames: u64) -> f64 {
+        match state.source {
+            ClockSource::Held => 0.,
+            ClockSource::Audio { sample_rate, rate, .. } => {
+                frames.saturating_sub(state.base_frames) as f64 / sample_rate as f64 * rate
+            }
+            ClockSource::Wall { .. } if self.is_paused() => 0.,
+            ClockSource::Wall { since } => since.elapsed().as_secs_f64() * state.rate,
+        }
+    }
+
+    /// Rebases the clock at the rate changes the output has reached by `frames` played frames.
+    fn apply_rate_changes(&self, state: &mut ClockState, frames: u64) {
+        loop {
+            let ClockSource::Audio { rate_changes, .. } = &mut state.source else { return };
+            if rate_changes.front().is_none_or(|rate_change| rate_change.frames > frames) {
+                return;
+            }
+            let rate_change = rate_changes.pop_front().unwrap();
+            let position = rate_change
+                .position
+                .unwrap_or_else(|| state.base_position + self.elapsed(state, rate_change.frames));
+            state.base_position = position;
+            state.base_frames = rate_change.frames;
+            if let ClockSource::Audio { rate, .. } = &mut state.source {
+                *rate = rate_change.rate;
+            }
+        }
+    }
+
+    /// Stops the clock at the audio position `position` (in seconds), e.g. after a seek.
+    pub fn reset(&self, position: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.base_position = position;
+        state.source = ClockSource::Held;
+    }
+
+    /// Lets the clock follow the audio output, starting at `position` (in seconds) with the
+    /// next sample the device consumes.
+    pub fn resume_with_audio(&self, position: f64, sample_rate: u32) {
+        let mut state = self.state.lock().unwrap();
+        self.frames_played.store(0, Ordering::SeqCst);
+        state.base_position = position;
+        state.base_frames = 0;
+        state.source = ClockSource::Audio { sample_rate, rate: state.rate, rate_changes: VecDeque::new() };
+    }
+
+    /// Tells the clock that the samples produced at `rate` start after `frames` played frames,
+    /// counted since [`Self::resume_with_audio`], at the media position `position` (in seconds)
+    /// if known. The clock keeps counting the samples before them at the previous rate.
+    pub fn change_audio_rate_at(&self, frames: u64, position: Option<f64>, rate: f64) {
+        let mut state = self.state.lock().unwrap();
+        if let ClockSource::Audio { rate_changes, .. } = &mut state.source {
+            rate_changes.push_back(AudioRateChange { frames, position, rate });
+        }
+    }
+
+    /// Lets the clock run on the wall clock from `position` (in seconds), for playback without
+    /// audio.
+    pub fn resume_with_wall(&self, position: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.base_position = position;
+        state.source = ClockSource::Wall { since: Instant::now() };
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Pauses or resumes the clock. The audio output must not consume any samples while paused.
+    pub fn set_paused(&self, paused: bool) {
+        let mut state = self.state.lock().unwrap();
+        if let ClockSource::Wall { .. } = state.source {
+            // The wall clock doesn't stop by itself, rebase on both ends of the pause
+            state.base_position += self.elapsed(&state, 0);
+            state.source = ClockSource::Wall { since: Instant::now() };
+        }
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+
+    /// The playback rate in media seconds per second of output.
+    pub fn rate(&self) -> f64 {
+        self.state.lock().unwrap().rate
+    }
+
+    /// Changes the playback rate, keeping the current position. While following the audio, the
+    /// clock only switches to the new rate with the audio, see [`Self::change_audio_rate_at`].
+    pub fn set_rate(&self, rate: f64) {
+        let mut state = self.state.lock().unwrap();
+        if let ClockSource::Wall { .. } = state.source {
+            state.base_position += self.elapsed(&state, 0);
+            state.source = ClockSource::Wall { since: Instant::now() };
+        }
+        state.rate = rate;
+    }
+
+    /// Plays the audio `audio_delay` seconds later than the video, or earlier if negative. Takes
+    /// effect right away, the video catches up by dropping or holding frames.
+    pub fn set_audio_delay(&self, audio_delay: f64) {
+        self.state.lock().unwrap().audio_delay = audio_delay;
+    }
+
+    /// Seconds the audio is played later than the video, negative if earlier.
+    pub fn audio_delay(&self) -> f64 {
+        self.state.lock().unwrap().audio_delay
+    }
+
+    /// Called from the audio output callback with the number of frames it consumed.
+    pub fn advance_audio(&self, frames: u64) {
+        self.frames_played.fetch_add(frames, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_position(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "position {} instead of {}", actual, expected);
+    }
+
+    #[test]
+    fn shifts_the_position_by_the_audio_delay_while_following_the_audio() {
+        let clock = PlaybackClock::new();
+        clock.set_audio_delay(-0.25);
+
+        // Held for the audio after a seek
+        clock.reset(10.);
+        assert_position(clock.position(), 9.75);
+        assert_position(clock.audio_position(), 10.);
+
+        clock.resume_with_audio(10., 1000);
+        clock.advance_audio(500);
+        assert_position(clock.position(), 10.25);
+        assert_position(clock.audio_position(), 10.5);
+
+        // The output doesn't consume samples while paused
+        clock.set_paused(true);
+        assert!(clock.is_paused());
+        assert_position(clock.position(), 10.25);
+        clock.set_paused(false);
+
+        clock.set_rate(2.);
+        clock.change_audio_rate_at(500, Some(10.5), 2.);
+        clock.advance_audio(250);
+        assert_position(clock.position(), 10.75);
+        assert_position(clock.audio_position(), 11.);
+    }
+
+    #[test]
+    fn runs_on_the_wall_clock_without_the_audio_delay() {
+        let clock = PlaybackClock::new();
+        clock.set_audio_delay(0.5);
+        clock.resume_with_wall(3.);
+        clock.set_paused(true);
+
+        let position = clock.position();
+        assert!((3. ..3.1).contains(&position));
+        assert_position(clock.audio_position(), position);
+
+        // Stands still while paused, also across rate changes
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert_position(clock.position(), position);
+        clock.set_rate(2.);
+        assert_position(clock.position(), position);
+    }
+
+    #[test]
+    fn switches_the_audio_rate_once_the_buffered_samples_are_played() {
+        let clock = PlaybackClock::new();
+        clock.resume_with_audio(10., 1000);
+        clock.advance_audio(500);
+
+        // 300 more frames are still buffered at normal speed when the rate changes
+        clock.set_rate(2.);
+        clock.change_audio_rate_at(800, Some(10.8), 2.);
+        assert_eq!(clock.rate(), 2.);
+        assert_position(clock.audio_position(), 10.5);
+
+        clock.advance_audio(200);
+        assert_position(clock.audio_position(), 10.7);
+        clock.advance_audio(200);
+        assert_position(clock.audio_position(), 11.);
+    }
+
+    #[test]
+    fn re_anchors_at_the_position_of_the_samples_at_the_new_rate() {
+        let clock = PlaybackClock::new();
+        clock.resume_with_audio(0., 1000);
+        clock.change_audio_rate_at(1000, Some(1.05), 0.5);
+        clock.change_audio_rate_at(2000, None, 1.);
+
+        clock.advance_audio(1000);
+        assert_position(clock.audio_position(), 1.05);
+        clock.advance_audio(500);
+        assert_position(clock.audio_position(), 1.3);
+        clock.advance_audio(1000);
+        assert_position(clock.audio_position(), 2.05);
+    }
+}
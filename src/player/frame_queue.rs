@@ -0,0 +1,228 @@
+// Copyright © SixtyFPS GmbH <info@slint.dev>
+// SPDX-License-Identifier: MIT
+
+use std::collections::VecDeque;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::clock::PlaybackClock;
+
+/// Frames beyond this many waiting for the renderer are dropped, oldest first.
+const FRAME_QUEUE_CAPACITY: usize = 3;
+
+/// Empty frames kept for reuse. Every queued frame, the one being decoded into and the one being
+/// uploaded need one, so this is enough to never allocate once playback is running.
+const FRAME_POOL_CAPACITY: usize = FRAME_QUEUE_CAPACITY + 2;
+
+/// Weight of a new sample in the moving average of the decode time.
+const DECODE_TIME_SMOOTHING: f64 = 0.05;
+
+/// Decoded video frames on their way from the video thread to the renderer.
+///
+/// The video thread pushes frames once they are due. The renderer takes only the most recent due
+/// frame on every redraw, so when it can't keep up the frames it skipped are dropped and counted,
+/// instead of piling up and adding latency.
+///
+/// Frames are handed over without copying: the video thread decodes into frames from the queue's
+/// pool and moves them in, so they keep referencing the decoder's buffers, and the renderer
+/// returns them to the pool when it drops the [`PooledFrame`].
+pub struct FrameQueue {
+    frames: Mutex<VecDeque<QueuedFrame>>,
+    pool: Arc<FramePool>,
+    clock: Arc<PlaybackClock>,
+    presented: AtomicU64,
+    dropped: AtomicU64,
+    /// Moving average of the seconds it takes to decode a frame, as f64 bits.
+    decode_time: AtomicU64,
+}
+
+struct QueuedFrame {
+    /// Presentation time in seconds, if the frame has one.
+    position: Option<f64>,
+    frame: ffmpeg_next::util::frame::Video,
+}
+
+impl FrameQueue {
+    pub(super) fn new(clock: Arc<PlaybackClock>) -> Self {
+        Self {
+            frames: Mutex::new(VecDeque::with_capacity(FRAME_QUEUE_CAPACITY)),
+            pool: Arc::new(FramePool { frames: Mutex::new(Vec::with_capacity(FRAME_POOL_CAPACITY)) }),
+            clock,
+            presented: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            decode_time: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    /// An empty frame to decode into, recycled if possible.
+    pub(super) fn take_frame(&self) -> ffmpeg_next::util::frame::Video {
+        self.pool.take()
+    }
+
+    /// Returns a frame from [`Self::take_frame`] to the pool without pushing it.
+    pub(super) fn recycle(&self, frame: ffmpeg_next::util::frame::Video) {
+        self.pool.recycle(frame);
+    }
+
+    pub(super) fn push(&self, frame: ffmpeg_next::util::frame::Video, position: Option<f64>) {
+        let mut frames = self.frames.lock().unwrap();
+        while frames.len() >= FRAME_QUEUE_CAPACITY {
+            if let Some(dropped) = frames.pop_front() {
+                self.pool.recycle(dropped.frame);
+            }
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        frames.push_back(QueuedFrame { position, frame });
+    }
+
+    /// Counts a frame the video thread dropped because it was already late.
+    pub(super) fn record_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Adds the time the decoder took for a frame to the moving average.
+    pub(super) fn record_decode_time(&self, decode_time: Duration) {
+        // Only the video thread records, so the load and store don't race
+        let average = f64::from_bits(self.decode_time.load(Ordering::Relaxed));
+        let sample = decode_time.as_secs_f64();
+        let average =
+            if average == 0. { sample } else { average + (sample - average) * DECODE_TIME_SMOOTHING };
+        self.decode_time.store(average.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Discards the queued frames after a seek.
+    pub(super) fn clear(&self) {
+        let mut frames = self.frames.lock().unwrap();
+        frames.drain(..).for_each(|queued| self.pool.recycle(queued.frame));
+    }
+
+    /// Takes the most recent frame that is due according to the playback clock. Older due frames
+    /// are dropped, as they were superseded before they could be shown.
+    pub fn pop(&self) -> Option<PooledFrame> {
+        let position = self.clock.position();
+        let mut frames = self.frames.lock().unwrap();
+
+        let due = frames
+            .iter()
+            .take_while(|queued| queued.position.is_none_or(|frame_position| frame_position <= position))
+            .count();
+        if due == 0 {
+            return None;
+        }
+        frames.drain(..due - 1).for_each(|queued| self.pool.recycle(queued.frame));
+        self.dropped.fetch_add(due as u64 - 1, Ordering::Relaxed);
+
+        let queued = frames.pop_front()?;
+        self.presented.fetch_add(1, Ordering::Relaxed);
+        Some(PooledFrame { frame: Some(queued.frame), pool: self.pool.clone() })
+    }
+
+    /// Number of frames handed to the renderer.
+    pub fn presented_frames(&self) -> u64 {
+        self.presented.load(Ordering::Relaxed)
+    }
+
+    /// Number of frames that were decoded but never shown because they were late.
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Average time it recently took to decode a frame. Decoding keeps up as long as this stays
+    /// below the frame duration.
+    pub fn decode_time(&self) -> Duration {
+        Duration::from_secs_f64(f64::from_bits(self.decode_time.load(Ordering::Relaxed)))
+    }
+}
+
+struct FramePool {
+    frames: Mutex<Vec<ffmpeg_next::util::frame::Video>>,
+}
+
+impl FramePool {
+    fn take(&self) -> ffmpeg_next::util::frame::Video {
+        self.frames.lock().unwrap().pop().unwrap_or_else(ffmpeg_next::util::frame::Video::empty)
+    }
+
+    fn recycle(&self, mut frame: ffmpeg_next::util::frame::Video) {
+        // Release the decoder's buffers right away, only the empty frame is kept
+        unsafe { ffmpeg_next::ffi::av_frame_unref(frame.as_mut_ptr()) };
+        let mut frames = self.frames.lock().unwrap();
+        if frames.len() < FRAME_POOL_CAPACITY {
+            frames.push(frame);
+        }
+    }
+}
+
+/// A decoded frame taken from the [`FrameQueue`], returned to its pool when dropped.
+pub struct PooledFrame {
+    frame: Option<ffmpeg_next::util::frame::Video>,
+    pool: Arc<FramePool>,
+}
+
+impl Deref for PooledFrame {
+    type Target = ffmpeg_next::util::frame::Video;
+
+    fn deref(&self) -> &Self::Target {
+        self.frame.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledFrame {
+    fn drop(&mut self) {
+        if let Some(frame) = self.frame.take() {
+            self.pool.recycle(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Queues frames with the given pts, presented at a tenth of a second per pts.
+    fn push_frames(queue: &FrameQueue, pts: std::ops::Range<i64>) {
+        for pts in pts {
+            let mut frame = queue.take_frame();
+            frame.set_pts(Some(pts));
+            queue.push(frame, Some(pts as f64 / 10.));
+        }
+    }
+
+    #[test]
+    fn presents_the_latest_due_frame_and_drops_the_ones_it_supersedes() {
+        let clock = Arc::new(PlaybackClock::new());
+        let queue = FrameQueue::new(clock.clone());
+        push_frames(&queue, 0..3);
+
+        clock.resume_with_audio(0., 1000);
+        assert_eq!(queue.pop().map(|frame| frame.pts()), Some(Some(0)));
+        assert!(queue.pop().is_none());
+
+        // Both remaining frames are due at once, only the later one is shown
+        clock.advance_audio(250);
+        assert_eq!(queue.pop().map(|frame| frame.pts()), Some(Some(2)));
+        assert!(queue.pop().is_none());
+        assert_eq!((queue.presented_frames(), queue.dropped_frames()), (2, 1));
+    }
+
+    #[test]
+    fn drops_the_oldest_frames_beyond_its_capacity() {
+        let clock = Arc::new(PlaybackClock::new());
+        let queue = FrameQueue::new(clock.clone());
+        push_frames(&queue, 0..FRAME_QUEUE_CAPACITY as i64 + 2);
+        assert_eq!(queue.dropped_frames(), 2);
+
+        // Frames that aren't due yet stay queued in order
+        clock.resume_with_audio(0.2, 1000);
+        assert_eq!(queue.pop().map(|frame| frame.pts()), Some(Some(2)));
+        clock.advance_audio(150);
+        assert_eq!(queue.pop().map(|frame| frame.pts()), Some(Some(3)));
+        assert_eq!((queue.presented_frames(), queue.dropped_frames()), (2, 2));
+
+        queue.clear();
+        clock.advance_audio(1000);
+        assert!(queue.pop().is_none());
+    }
+}
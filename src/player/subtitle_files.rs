@@ -0,0 +1,231 @@
+// Copyright © SixtyFPS GmbH <info@slint.dev>
+// SPDX-License-Identifier: MIT
+
+use std::path::{Path, PathBuf};
+
+use super::subtitles::{strip_ass_markup, SubtitleCue};
+
+/// Extensions of the sidecar subtitle files we look for next to a video.
+const SUBTITLE_EXTENSIONS: [&str; 6] = ["srt", "ass", "ssa", "vtt", "sub", "idx"];
+
+/// Finds subtitle files next to `video_path` whose name starts with the video's file stem, e.g.
+/// `Horimiya_01.srt`, `Horimiya_01.ja.ass` or `Horimiya_01_en.vtt` for `Horimiya_01.mkv`, sorted by
+/// file name.
+pub fn find_subtitle_files(video_path: &Path) -> Vec<PathBuf> {
+    let (Some(directory), Some(video_stem)) = (video_path.parent(), video_path.file_stem()) else {
+        return Vec::new();
+    };
+    let directory = if directory.as_os_str().is_empty() { Path::new(".") } else { directory };
+    let video_stem = video_stem.to_string_lossy();
+
+    let Ok(entries) = std::fs::read_dir(directory) else {
+        return Vec::new();
+    };
+
+    let mut subtitle_files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            let has_subtitle_extension = path.extension().is_some_and(|extension| {
+                SUBTITLE_EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str())
+            });
+            // The stem has to end or be followed by a separator, so that `Show_1` doesn't pick up
+            // `Show_10.srt`
+            let matches_video = path.file_stem().is_some_and(|stem| {
+                stem.to_string_lossy()
+                    .strip_prefix(video_stem.as_ref())
+                    .is_some_and(|suffix| suffix.is_empty() || suffix.starts_with(['.', '_', '-']))
+            });
+            has_subtitle_extension && matches_video && path.is_file()
+        })
+        .collect();
+
+    // A .sub next to an .idx is the VobSub bitmap data, which is loaded through the index.
+    let vobsub_indices: Vec<PathBuf> = subtitle_files
+        .iter()
+        .filter(|path| path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("idx")))
+        .map(|path| path.with_extension(""))
+        .collect();
+    subtitle_files.retain(|path| {
+        !(path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("sub"))
+            && vobsub_indices.contains(&path.with_extension("")))
+    });
+
+    subtitle_files.sort();
+    subtitle_files
+}
+
+/// Parses an external subtitle file into cues, using `fps` for frame based formats (MicroDVD).
+///
+/// SubRip, SubStation Alpha, MicroDVD and VobSub index files are parsed with subparse, in the
+/// encoding given by their byte order mark, as UTF-8 if they are valid UTF-8, and otherwise in the
+/// encoding subparse detects, e.g. Shift-JIS. WebVTT is always UTF-8 and close enough to SubRip to
+/// be converted to it first. VobSub tracks only carry bitmaps, so they don't produce any text cues.
+pub fn load_subtitle_file(path: &Path, fps: f64) -> Result<Vec<SubtitleCue>, anyhow::Error> {
+    let content = std::fs::read(path)?;
+
+    let is_webvtt =
+        path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("vtt"));
+
+    let (format, subtitle_file) = if is_webvtt {
+        let content = String::from_utf8_lossy(&content);
+        let content = webvtt_to_srt(content.trim_start_matches('\u{feff}'));
+        let format = subparse::SubtitleFormat::SubRip;
+        (format, subparse::parse_str(format, &content, fps))
+    } else {
+        let format = subparse::get_subtitle_format(path.extension(), &content)
+            .ok_or_else(|| anyhow::anyhow!("unknown subtitle format of {}", path.display()))?;
+        (format, subparse::parse_bytes(format, &content, text_encoding(&content), fps))
+    };
+    let is_ass = matches!(format, subparse::SubtitleFormat::SubStationAlpha);
+
+    let subtitle_file = subtitle_file
+        .map_err(|err| anyhow::anyhow!("error parsing {}: {}", path.display(), err))?;
+    let entries = subtitle_file
+        .get_subtitle_entries()
+        .map_err(|err| anyhow::anyhow!("error reading entries of {}: {}", path.display(), err))?;
+
+    let mut cues: Vec<SubtitleCue> = entries
+        .into_iter()
+        .filter_map(|entry| {
+            let line = entry.line?;
+            let text = if is_ass { strip_ass_markup(&line) } else { strip_html_tags(&line) };
+            (!text.is_empty()).then(|| SubtitleCue {
+                start: entry.timespan.start.msecs() as f64 / 1000.,
+                end: Some(entry.timespan.end.msecs() as f64 / 1000.),
+                text,
+                style: None,
+            })
+        })
+        .collect();
+    cues.sort_by(|a, b| a.start.total_cmp(&b.start));
+
+    Ok(cues)
+}
+
+/// The encoding of a text subtitle file if it is certain, `None` to leave the guess to subparse.
+fn text_encoding(content: &[u8]) -> Option<&'static encoding_rs::Encoding> {
+    match encoding_rs::Encoding::for_bom(content) {
+        Some((encoding, _)) => Some(encoding),
+        None => std::str::from_utf8(content).is_ok().then_some(encoding_rs::UTF_8),
+    }
+}
+
+/// Removes the `<i>`, `<b>`, `<font ...>` etc. tags SubRip and WebVTT use for formatting.
+fn strip_html_tags(raw_text: &str) -> String {
+    let mut text = String::with_capacity(raw_text.len());
+    let mut in_tag = false;
+    for c in raw_text.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if in_tag => {}
+            _ => text.push(c),
+        }
+    }
+    text.trim().to_string()
+}
+
+/// Rewrites WebVTT cues as SubRip: drops the header, `NOTE`, `STYLE` and `REGION` blocks and cue
+/// settings, and turns `00:01.500` timestamps into `00:00:01,500`.
+fn webvtt_to_srt(content: &str) -> String {
+    let mut srt = String::with_capacity(content.len());
+    let mut index = 0;
+
+    for block in content.replace("\r\n", "\n").split("\n\n") {
+        let mut lines = block.lines().skip_while(|line| line.trim().is_empty()).peekable();
+
+        // An optional cue identifier precedes the timing line
+        if lines.peek().is_some_and(|line| !line.contains("-->")) {
+            lines.next();
+        }
+        let Some(timing) = lines.next().filter(|line| line.contains("-->")) else {
+            continue;
+        };
+
+        let mut timestamps = timing.split("-->").map(|timestamp| {
+            let timestamp = timestamp.split_whitespace().next().unwrap_or_default();
+            let timestamp = if timestamp.matches(':').count() == 1 {
+                format!("00:{}", timestamp)
+            } else {
+                timestamp.to_string()
+            };
+            timestamp.replace('.', ",")
+        });
+        let (Some(start), Some(end)) = (timestamps.next(), timestamps.next()) else {
+            continue;
+        };
+
+        index += 1;
+        srt.push_str(&format!("{}\n{} --> {}\n", index, start, end));
+        for line in lines {
+            srt.push_str(line);
+            srt.push('\n');
+        }
+        srt.push('\n');
+    }
+
+    srt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_subtitle_files_of_the_video_only() {
+        let directory = std::env::temp_dir().join(format!("sentoani-subtitle-files-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        for name in [
+            "Show_1.mkv",
+            "Show_1.srt",
+            "Show_1.ja.ass",
+            "Show_1_en.vtt",
+            "Show_1-signs.ssa",
+            "Show_10.srt",
+            "Show_1x.srt",
+            "Show_1.txt",
+            "Show_1.idx",
+            "Show_1.sub",
+            "Show_2.srt",
+        ] {
+            std::fs::write(directory.join(name), "").unwrap();
+        }
+
+        let found = find_subtitle_files(&directory.join("Show_1.mkv"));
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let names: Vec<_> =
+            found.iter().map(|path| path.file_name().unwrap().to_string_lossy().into_owned()).collect();
+        assert_eq!(names, ["Show_1-signs.ssa", "Show_1.idx", "Show_1.ja.ass", "Show_1.srt", "Show_1_en.vtt"]);
+    }
+
+    #[test]
+    fn loads_subtitle_files_in_their_encoding() {
+        let directory = std::env::temp_dir().join(format!("sentoani-subtitle-encodings-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let srt = "1\r\n00:00:01,000 --> 00:00:02,500\r\nこんにちは、世界。今日はいい天気ですね。\r\n\r\n2\r\n00:00:03,000 --> 00:00:04,000\r\n<i>さようなら</i>\r\n";
+
+        let (shift_jis, _, _) = encoding_rs::SHIFT_JIS.encode(srt);
+        let utf16: Vec<u8> = [0xff, 0xfe].into_iter().chain(srt.encode_utf16().flat_map(u16::to_le_bytes)).collect();
+        let utf8: Vec<u8> = [0xef, 0xbb, 0xbf].into_iter().chain(srt.bytes()).collect();
+
+        for (name, content) in [("shift_jis.srt", &shift_jis[..]), ("utf16.srt", &utf16), ("utf8.srt", &utf8)] {
+            let path = directory.join(name);
+            std::fs::write(&path, content).unwrap();
+            let cues = load_subtitle_file(&path, 25.).unwrap();
+            let texts: Vec<_> = cues.iter().map(|cue| cue.text.as_str()).collect();
+            assert_eq!(texts, ["こんにちは、世界。今日はいい天気ですね。", "さようなら"], "{}", name);
+            assert_eq!((cues[0].start, cues[0].end), (1., Some(2.5)));
+        }
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn converts_webvtt_to_srt() {
+        let webvtt = "WEBVTT\r\n\r\nNOTE a comment\r\n\r\nintro\r\n00:01.500 --> 00:03.000 align:start\r\nHello\r\nthere\r\n\r\n01:02:03.004 --> 01:02:04.000\r\n<i>Bye</i>\r\n";
+        assert_eq!(
+            webvtt_to_srt(webvtt),
+            "1\n00:00:01,500 --> 00:00:03,000\nHello\nthere\n\n2\n01:02:03,004 --> 01:02:04,000\n<i>Bye</i>\n\n"
+        );
+    }
+}
@@ -1,65 +1,158 @@
-use std::pin::Pin;
+use std::ffi::CStr;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use bytemuck::Pod;
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::SizedSample;
-
-use ffmpeg_next::subtitle::Text;
-use ffmpeg_next::Packet;
 use futures::future::OptionFuture;
 use futures::FutureExt;
-use ringbuf::ring_buffer::{RbRef, RbWrite};
-use ringbuf::HeapRb;
-use std::future::Future;
 
-use super::ControlCommand;
+use super::{clock::PlaybackClock, PacketMessage, PlaybackCommand};
+
+/// Upper bound (in seconds) for a single sleep while waiting for the next cue to start or end.
+const MAX_CUE_WAIT: f64 = 0.05;
+
+/// A decoded subtitle line. Times are in seconds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SubtitleCue {
+    pub start: f64,
+    /// `None` if the stream doesn't say, in which case the cue stays until the next one.
+    pub end: Option<f64>,
+    /// Plain text with ASS override tags removed and line breaks as `\n`.
+    pub text: String,
+    /// The ASS/SSA style name, if the track has styles.
+    pub style: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub enum SubtitleEvent {
+    Show(SubtitleCue),
+    Hide(SubtitleCue),
+    /// Hide every cue of the track, sent when the track is switched off.
+    Clear,
+}
 
 pub struct SubtitlesPlaybackThread {
-    control_sender: smol::channel::Sender<ControlCommand>,
-    packet_sender: smol::channel::Sender<ffmpeg_next::codec::packet::packet::Packet>,
+    control_sender: smol::channel::Sender<PlaybackCommand>,
+    packet_sender: smol::channel::Sender<PacketMessage>,
+    packet_drain: smol::channel::Receiver<PacketMessage>,
+    flushing: Arc<AtomicBool>,
     receiver_thread: Option<std::thread::JoinHandle<()>>,
 }
 
+/// Where a subtitle track's cues come from.
+enum CueSource {
+    /// An embedded stream, decoded from the packets the demuxer forwards.
+    Stream { packet_decoder: ffmpeg_next::decoder::Subtitle, time_base_seconds: f64 },
+    /// An external subtitle file, parsed up front.
+    File(Vec<SubtitleCue>),
+}
+
 impl SubtitlesPlaybackThread {
     pub fn start(
         stream: &ffmpeg_next::format::stream::Stream,
-        mut subtitle_frame_callback: Box<dyn FnMut(&ffmpeg_next::Packet) + Send>,
+        clock: Arc<PlaybackClock>,
+        subtitle_event_callback: Box<dyn FnMut(SubtitleEvent) + Send>,
+    ) -> Result<Self, anyhow::Error> {
+        let decoder_context = ffmpeg_next::codec::Context::from_parameters(stream.parameters())?;
+        let packet_decoder = decoder_context.decoder().subtitle()?;
+
+        let time_base = stream.time_base();
+        let time_base_seconds = time_base.numerator() as f64 / time_base.denominator() as f64;
+
+        Self::start_with_source(
+            CueSource::Stream { packet_decoder, time_base_seconds },
+            clock,
+            subtitle_event_callback,
+        )
+    }
+
+    /// Plays cues loaded from an external subtitle file. Only flushes are sent to such a thread,
+    /// to re-schedule the cues after a seek.
+    pub fn start_external(
+        cues: Vec<SubtitleCue>,
+        clock: Arc<PlaybackClock>,
+        subtitle_event_callback: Box<dyn FnMut(SubtitleEvent) + Send>,
+    ) -> Result<Self, anyhow::Error> {
+        Self::start_with_source(CueSource::File(cues), clock, subtitle_event_callback)
+    }
+
+    fn start_with_source(
+        mut source: CueSource,
+        clock: Arc<PlaybackClock>,
+        mut subtitle_event_callback: Box<dyn FnMut(SubtitleEvent) + Send>,
     ) -> Result<Self, anyhow::Error> {
         let (control_sender, control_receiver) = smol::channel::unbounded();
 
-        let (packet_sender, packet_receiver) = smol::channel::bounded::<Packet>(128);
+        let (packet_sender, packet_receiver) = smol::channel::bounded::<PacketMessage>(128);
+        let packet_drain = packet_receiver.clone();
 
-        let decoder_context = ffmpeg_next::codec::Context::from_parameters(stream.parameters())?;
-        let mut packet_decoder = decoder_context.decoder().subtitle()?;
+        let flushing = Arc::new(AtomicBool::new(false));
+        let thread_flushing = flushing.clone();
 
         let receiver_thread =
             std::thread::Builder::new().name("subtitle playback thread".into()).spawn(move || {
                 smol::block_on(async move {
                     let packet_receiver_impl = async {
+                        let mut scheduler = CueScheduler::default();
+                        if let CueSource::File(cues) = &source {
+                            cues.iter().cloned().for_each(|cue| scheduler.push(cue));
+                        }
+
                         loop {
-                            let Ok(packet) = packet_receiver.recv().await else { break };
+                            // Show and hide everything that is due, then sleep until the next
+                            // cue changes. The sleep is capped so that seeks, pauses and rate
+                            // changes of the playback clock are picked up.
+                            let position = clock.position();
+                            let wait = scheduler
+                                .update(position, &mut subtitle_event_callback)
+                                .map(|next_change| {
+                                    if clock.is_paused() {
+                                        MAX_CUE_WAIT
+                                    } else {
+                                        ((next_change - position) / clock.rate()).clamp(0., MAX_CUE_WAIT)
+                                    }
+                                });
+                            let mut cue_timer: OptionFuture<_> = wait
+                                .map(|wait| smol::Timer::after(Duration::from_secs_f64(wait)).fuse())
+                                .into();
+
+                            let message = futures::select! {
+                                message = packet_receiver.recv().fuse() => message,
+                                _ = cue_timer => continue,
+                            };
+                            let Ok(message) = message else { break };
+
+                            let packet = match message {
+                                PacketMessage::Packet(packet) => packet,
+                                PacketMessage::Flush(_) => {
+                                    // Cues that already ended at the new position are dropped by
+                                    // the scheduler, so there is nothing else to skip here.
+                                    scheduler.clear(&mut subtitle_event_callback);
+                                    match &mut source {
+                                        CueSource::Stream { packet_decoder, .. } => packet_decoder.flush(),
+                                        CueSource::File(cues) => {
+                                            cues.iter().cloned().for_each(|cue| scheduler.push(cue))
+                                        }
+                                    }
+                                    thread_flushing.store(false, Ordering::SeqCst);
+                                    continue;
+                                }
+                            };
 
-                            // TODO: Properly await until we get a new packet with data to call the callback... Maybe something is built in...
-                            // Or just wait util data.len() > 0?
-                            println!("Received subtitle packet {:?}", packet.data().unwrap());
-                            
                             smol::future::yield_now().await;
 
-                            subtitle_frame_callback(&packet);
-
-                            // while packet_decoder.decode(&packet, &mut subtitles).is_ok() {
-
-                            //     // TODO: Report this as a bug, getting seg fault on reading utf8 string as unchecked str conversion? Maybe?
-                            //     // https://ffmpeg.org/doxygen/trunk/structAVSubtitleRect.html#a893b1c87ee3d1816a0149ab3005fdd9e
-                            //     // Per the docs, needs to be 0 terminated... Looks like base on the data, it is not 0 terminated...
-                            //     // unsafe {
-                            //     //     println!("Subtitles count: {}", subtitles.rects().len());
-                            //     //     for rect in subtitles.rects() {
-                            //     //         let text = Text::wrap(rect.as_ptr());
-                            //     //         println!("Subtitle text: {:?}", rect.as_ptr());
-                            //     //     }
-                            //     // }
-                            // }
+                            if thread_flushing.load(Ordering::SeqCst) {
+                                continue;
+                            }
+
+                            let CueSource::Stream { packet_decoder, time_base_seconds } = &mut source else {
+                                continue;
+                            };
+                            match decode_cues(packet_decoder, &packet, *time_base_seconds) {
+                                Ok(cues) => cues.into_iter().for_each(|cue| scheduler.push(cue)),
+                                Err(err) => eprintln!("error decoding subtitle packet: {}", err),
+                            }
                         }
                     }
                     .fuse()
@@ -77,10 +170,10 @@ impl SubtitlesPlaybackThread {
                             _ = packet_receiver => {},
                             received_command = control_receiver.recv().fuse() => {
                                 match received_command {
-                                    Ok(ControlCommand::Pause) => {
+                                    Ok(PlaybackCommand::Pause) => {
                                         playing = false;
                                     }
-                                    Ok(ControlCommand::Play) => {
+                                    Ok(PlaybackCommand::Play) => {
                                         playing = true;
                                     }
                                     Err(_) => {
@@ -94,17 +187,31 @@ impl SubtitlesPlaybackThread {
                 })
             })?;
 
-        Ok(Self { control_sender, packet_sender, receiver_thread: Some(receiver_thread) })
+        Ok(Self {
+            control_sender,
+            packet_sender,
+            packet_drain,
+            flushing,
+            receiver_thread: Some(receiver_thread),
+        })
     }
 
     pub async fn receive_packet(&self, packet: ffmpeg_next::codec::packet::packet::Packet) -> bool {
-        match self.packet_sender.send(packet).await {
+        match self.packet_sender.send(PacketMessage::Packet(packet)).await {
             Ok(_) => return true,
             Err(smol::channel::SendError(_)) => return false,
         }
     }
 
-    pub async fn send_control_message(&self, message: ControlCommand) {
+    /// Discards all queued packets and hides the current cues, resuming with the cues still
+    /// visible at `position` (in seconds).
+    pub async fn flush(&self, position: f64) {
+        self.flushing.store(true, Ordering::SeqCst);
+        while self.packet_drain.try_recv().is_ok() {}
+        let _ = self.packet_sender.send(PacketMessage::Flush(position)).await;
+    }
+
+    pub async fn send_control_message(&self, message: PlaybackCommand) {
         self.control_sender.send(message).await.unwrap();
     }
 }
@@ -116,4 +223,233 @@ impl Drop for SubtitlesPlaybackThread {
             receiver_join_handle.join().unwrap();
         }
     }
-}
\ No newline at end of file
+}
+
+/// Decoded cues waiting for their presentation time, and the ones currently shown.
+#[derive(Default)]
+struct CueScheduler {
+    /// Ordered by start time.
+    pending: VecDeque<SubtitleCue>,
+    active: Vec<SubtitleCue>,
+}
+
+impl CueScheduler {
+    fn push(&mut self, cue: SubtitleCue) {
+        let index = self.pending.partition_point(|pending_cue| pending_cue.start <= cue.start);
+        self.pending.insert(index, cue);
+    }
+
+    /// Hides all active cues and forgets the pending ones.
+    fn clear(&mut self, callback: &mut dyn FnMut(SubtitleEvent)) {
+        self.pending.clear();
+        for cue in self.active.drain(..) {
+            callback(SubtitleEvent::Hide(cue));
+        }
+    }
+
+    /// Emits the show and hide events due at `position` (in seconds) and returns the position
+    /// of the next change, if any.
+    fn update(&mut self, position: f64, callback: &mut dyn FnMut(SubtitleEvent)) -> Option<f64> {
+        self.active.retain(|cue| {
+            let ended = cue.end.is_some_and(|end| end <= position);
+            if ended {
+                callback(SubtitleEvent::Hide(cue.clone()));
+            }
+            !ended
+        });
+
+        while self.pending.front().is_some_and(|cue| cue.start <= position) {
+            let cue = self.pending.pop_front().unwrap();
+            if cue.end.is_some_and(|end| end <= position) {
+                // Already over, e.g. when seeking past it
+                continue;
+            }
+
+            // Cues without an end are replaced by the next one
+            self.active.retain(|active_cue| {
+                if active_cue.end.is_none() {
+                    callback(SubtitleEvent::Hide(active_cue.clone()));
+                }
+                active_cue.end.is_some()
+            });
+
+            callback(SubtitleEvent::Show(cue.clone()));
+            self.active.push(cue);
+        }
+
+        self.pending
+            .front()
+            .map(|cue| cue.start)
+            .into_iter()
+            .chain(self.active.iter().filter_map(|cue| cue.end))
+            .min_by(f64::total_cmp)
+    }
+}
+
+/// Decodes a subtitle packet into text cues. Bitmap subtitles are skipped.
+fn decode_cues(
+    packet_decoder: &mut ffmpeg_next::decoder::Subtitle,
+    packet: &ffmpeg_next::Packet,
+    time_base_seconds: f64,
+) -> Result<Vec<SubtitleCue>, ffmpeg_next::Error> {
+    let mut subtitle = ffmpeg_next::Subtitle::new();
+    if !packet_decoder.decode(packet, &mut subtitle)? {
+        return Ok(Vec::new());
+    }
+
+    let packet_start = packet.pts().map_or(0., |pts| pts as f64 * time_base_seconds);
+    let start = packet_start + subtitle.start() as f64 / 1000.;
+    let end = if subtitle.end() > subtitle.start() {
+        Some(packet_start + subtitle.end() as f64 / 1000.)
+    } else if packet.duration() > 0 {
+        Some(packet_start + packet.duration() as f64 * time_base_seconds)
+    } else {
+        None
+    };
+
+    let mut cues = Vec::new();
+    for rect in subtitle.rects() {
+        // The Text/Ass wrappers read their string with CStr::from_ptr and from_utf8_unchecked,
+        // without looking at the rect type first. Reading `text` of an ASS rect dereferences a
+        // null pointer, and invalid UTF-8 is undefined behaviour, so read the right field by hand.
+        let (raw, is_ass) = unsafe {
+            match rect {
+                ffmpeg_next::subtitle::Rect::Text(ref text) => ((*text.as_ptr()).text, false),
+                ffmpeg_next::subtitle::Rect::Ass(ref ass) => ((*ass.as_ptr()).ass, true),
+                _ => continue,
+            }
+        };
+        if raw.is_null() {
+            continue;
+        }
+        let raw = unsafe { CStr::from_ptr(raw) }.to_string_lossy();
+
+        let (text, style) = if is_ass { parse_ass_dialogue(&raw) } else { (raw.trim().to_string(), None) };
+        if text.is_empty() {
+            continue;
+        }
+
+        cues.push(SubtitleCue { start, end, text, style });
+    }
+
+    unsafe { ffmpeg_next::ffi::avsubtitle_free(subtitle.as_mut_ptr()) };
+
+    Ok(cues)
+}
+
+/// Extracts the text and style name from an ASS event line as produced by ffmpeg, either
+/// `ReadOrder,Layer,Style,Name,MarginL,MarginR,MarginV,Effect,Text` or a full
+/// `Dialogue: Layer,Start,End,Style,Name,MarginL,MarginR,MarginV,Effect,Text` line.
+fn parse_ass_dialogue(line: &str) -> (String, Option<String>) {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let (fields, style_index) = match line.strip_prefix("Dialogue:") {
+        Some(dialogue) => (dialogue.trim_start().splitn(10, ',').collect::<Vec<_>>(), 3),
+        None => (line.splitn(9, ',').collect::<Vec<_>>(), 2),
+    };
+
+    let Some(raw_text) = fields.last().filter(|_| fields.len() > style_index + 1) else {
+        return (line.to_string(), None);
+    };
+    let style = Some(fields[style_index].trim().to_string()).filter(|style| !style.is_empty());
+
+    (strip_ass_markup(raw_text), style)
+}
+
+/// Removes ASS override blocks such as `{\i1}` and turns `\N` line breaks and `\h` hard
+/// spaces into plain text.
+pub fn strip_ass_markup(raw_text: &str) -> String {
+    let mut text = String::with_capacity(raw_text.len());
+    let mut in_override = false;
+    let mut chars = raw_text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => in_override = true,
+            '}' if in_override => in_override = false,
+            _ if in_override => {}
+            '\\' => match chars.peek() {
+                Some('N') | Some('n') => {
+                    chars.next();
+                    text.push('\n');
+                }
+                Some('h') => {
+                    chars.next();
+                    text.push('\u{a0}');
+                }
+                _ => text.push(c),
+            },
+            _ => text.push(c),
+        }
+    }
+
+    text.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cue(start: f64, end: Option<f64>, text: &str) -> SubtitleCue {
+        SubtitleCue { start, end, text: text.to_string(), style: None }
+    }
+
+    /// Updates the scheduler at `position`, returning the events as `+text` for shown and `-text`
+    /// for hidden cues, and the position of the next change.
+    fn update(scheduler: &mut CueScheduler, position: f64) -> (Vec<String>, Option<f64>) {
+        let mut events = Vec::new();
+        let next_change = scheduler.update(position, &mut |event| {
+            events.push(match event {
+                SubtitleEvent::Show(cue) => format!("+{}", cue.text),
+                SubtitleEvent::Hide(cue) => format!("-{}", cue.text),
+                SubtitleEvent::Clear => String::from("clear"),
+            })
+        });
+        (events, next_change)
+    }
+
+    #[test]
+    fn stacks_overlapping_cues_and_hides_them_when_they_end() {
+        let mut scheduler = CueScheduler::default();
+        scheduler.push(cue(2., Some(5.), "second"));
+        scheduler.push(cue(1., Some(3.), "first"));
+        scheduler.push(cue(2., Some(4.), "third"));
+
+        assert_eq!(update(&mut scheduler, 0.5), (vec![], Some(1.)));
+        assert_eq!(update(&mut scheduler, 1.), (vec!["+first".into()], Some(2.)));
+        assert_eq!(update(&mut scheduler, 2.), (vec!["+second".into(), "+third".into()], Some(3.)));
+        assert_eq!(update(&mut scheduler, 3.), (vec!["-first".into()], Some(4.)));
+        assert_eq!(update(&mut scheduler, 4.5), (vec!["-third".into()], Some(5.)));
+        assert_eq!(update(&mut scheduler, 5.), (vec!["-second".into()], None));
+    }
+
+    #[test]
+    fn replaces_cues_without_an_end_and_skips_cues_already_over() {
+        let mut scheduler = CueScheduler::default();
+        scheduler.push(cue(1., None, "open"));
+        scheduler.push(cue(2., Some(3.), "over"));
+        scheduler.push(cue(4., Some(6.), "later"));
+        scheduler.push(cue(5., None, "next"));
+
+        assert_eq!(update(&mut scheduler, 1.), (vec!["+open".into()], Some(2.)));
+        // Jumping past a cue, e.g. after a seek, doesn't show it
+        assert_eq!(update(&mut scheduler, 3.5), (vec![], Some(4.)));
+        assert_eq!(
+            update(&mut scheduler, 5.),
+            (vec!["-open".into(), "+later".into(), "+next".into()], Some(6.))
+        );
+    }
+
+    #[test]
+    fn parses_ass_event_lines() {
+        assert_eq!(
+            parse_ass_dialogue("0,0,Default,,0,0,0,,{\\i1}Hello{\\i0}\\Nthere, you\r\n"),
+            ("Hello\nthere, you".to_string(), Some("Default".to_string()))
+        );
+        assert_eq!(
+            parse_ass_dialogue("Dialogue: 0,0:00:01.00,0:00:02.00,Signs,,0,0,0,,Station"),
+            ("Station".to_string(), Some("Signs".to_string()))
+        );
+        assert_eq!(parse_ass_dialogue("0,0,,,0,0,0,,Text"), ("Text".to_string(), None));
+        // Not an event line, kept as is
+        assert_eq!(parse_ass_dialogue("plain text"), ("plain text".to_string(), None));
+    }
+}
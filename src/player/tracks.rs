@@ -0,0 +1,119 @@
+// Copyright © SixtyFPS GmbH <info@slint.dev>
+// SPDX-License-Identifier: MIT
+
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrackKind {
+    Video,
+    Audio,
+    Subtitle,
+}
+
+/// Where a track comes from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrackSource {
+    /// An embedded stream, by stream index.
+    Stream(usize),
+    /// An external subtitle file.
+    File(PathBuf),
+}
+
+/// A stream of the opened file, or an external subtitle file, that can be selected for playback.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Track {
+    pub kind: TrackKind,
+    pub source: TrackSource,
+    /// Language tag, e.g. `jpn` or `en`.
+    pub language: Option<String>,
+    pub codec: String,
+    pub title: Option<String>,
+    /// Number of channels of audio tracks.
+    pub channels: Option<u16>,
+}
+
+impl Track {
+    /// A short description for track menus, e.g. `#1 jpn - Commentary (aac, 2ch)`.
+    pub fn label(&self) -> String {
+        let mut label = match &self.source {
+            TrackSource::Stream(stream_index) => format!("#{}", stream_index),
+            TrackSource::File(_) => String::from("File"),
+        };
+        if let Some(language) = &self.language {
+            label.push(' ');
+            label.push_str(language);
+        }
+        if let Some(title) = &self.title {
+            label.push_str(" - ");
+            label.push_str(title);
+        }
+        match self.channels {
+            Some(channels) => label.push_str(&format!(" ({}, {}ch)", self.codec, channels)),
+            None => label.push_str(&format!(" ({})", self.codec)),
+        }
+        label
+    }
+}
+
+/// Lists the video, audio and subtitle streams of `input_context`, in stream order.
+pub fn stream_tracks(input_context: &ffmpeg_next::format::context::Input) -> Vec<Track> {
+    input_context
+        .streams()
+        .filter_map(|stream| {
+            let parameters = stream.parameters();
+            let kind = match parameters.medium() {
+                ffmpeg_next::media::Type::Video => TrackKind::Video,
+                ffmpeg_next::media::Type::Audio => TrackKind::Audio,
+                ffmpeg_next::media::Type::Subtitle => TrackKind::Subtitle,
+                _ => return None,
+            };
+
+            let channels = if kind == TrackKind::Audio {
+                ffmpeg_next::codec::Context::from_parameters(stream.parameters())
+                    .and_then(|decoder_context| decoder_context.decoder().audio())
+                    .map(|decoder| decoder.channels())
+                    .ok()
+            } else {
+                None
+            };
+
+            let metadata = stream.metadata();
+            Some(Track {
+                kind,
+                source: TrackSource::Stream(stream.index()),
+                language: metadata
+                    .get("language")
+                    .filter(|language| *language != "und")
+                    .map(String::from),
+                codec: parameters.id().name().to_string(),
+                title: metadata.get("title").map(String::from),
+                channels,
+            })
+        })
+        .collect()
+}
+
+/// Describes an external subtitle file found next to `video_path`. The language is taken from
+/// the part of the name between the video's file stem and the extension, e.g. `ja` for
+/// `Horimiya_01.ja.ass`.
+pub fn subtitle_file_track(subtitle_path: &Path, video_path: &Path) -> Track {
+    let subtitle_stem = subtitle_path.file_stem().unwrap_or_default().to_string_lossy();
+    let video_stem = video_path.file_stem().unwrap_or_default().to_string_lossy();
+    let language = subtitle_stem
+        .strip_prefix(video_stem.as_ref())
+        .map(|suffix| suffix.trim_matches(|c: char| c == '.' || c == '_' || c == '-'))
+        .filter(|suffix| !suffix.is_empty())
+        .map(String::from);
+
+    Track {
+        kind: TrackKind::Subtitle,
+        source: TrackSource::File(subtitle_path.to_path_buf()),
+        language,
+        codec: subtitle_path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default(),
+        title: subtitle_path.file_name().map(|file_name| file_name.to_string_lossy().into_owned()),
+        channels: None,
+    }
+}
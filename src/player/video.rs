@@ -1,97 +1,238 @@
 // Copyright © SixtyFPS GmbH <info@slint.dev>
 // SPDX-License-Identifier: MIT
 
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
 use futures::{future::OptionFuture, FutureExt};
 
-use super::ControlCommand;
+use super::{clock::PlaybackClock, frame_queue::FrameQueue, PacketMessage, PlaybackCommand};
+
+/// Frames that are later than this (in seconds) according to the playback clock are dropped
+/// instead of presented.
+const MAX_FRAME_LATENESS: f64 = 0.1;
+
+/// Upper bound for a single sleep while waiting for a frame to become due, so that changes of
+/// the playback clock are picked up.
+const MAX_FRAME_WAIT: f64 = 0.01;
+
+/// How the video decoder spreads its work over threads.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ThreadingMode {
+    /// Decode several frames at once. Works with any stream, at the cost of a frame of latency
+    /// per thread.
+    #[default]
+    Frame,
+    /// Decode the slices of a frame in parallel. Only helps streams encoded with several slices
+    /// per frame.
+    Slice,
+}
+
+/// Thread settings of the video decoder.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DecoderThreading {
+    pub mode: ThreadingMode,
+    /// Number of decoding threads, 0 for one per CPU core.
+    pub count: usize,
+}
+
+impl DecoderThreading {
+    fn config(&self) -> ffmpeg_next::codec::threading::Config {
+        ffmpeg_next::codec::threading::Config {
+            kind: match self.mode {
+                ThreadingMode::Frame => ffmpeg_next::codec::threading::Type::Frame,
+                ThreadingMode::Slice => ffmpeg_next::codec::threading::Type::Slice,
+            },
+            count: self.count,
+            ..Default::default()
+        }
+    }
+}
 
 pub struct VideoPlaybackThread {
-    control_sender: smol::channel::Sender<ControlCommand>,
-    packet_sender: smol::channel::Sender<ffmpeg_next::codec::packet::packet::Packet>,
+    control_sender: smol::channel::Sender<PlaybackCommand>,
+    packet_sender: smol::channel::Sender<PacketMessage>,
+    packet_drain: smol::channel::Receiver<PacketMessage>,
+    flushing: Arc<AtomicBool>,
     receiver_thread: Option<std::thread::JoinHandle<()>>,
 }
 
 impl VideoPlaybackThread {
     pub fn start(
         stream: &ffmpeg_next::format::stream::Stream,
-        mut video_frame_callback: Box<dyn FnMut(&ffmpeg_next::util::frame::Video) + Send>,
+        playback_clock: Arc<PlaybackClock>,
+        frame_queue: Arc<FrameQueue>,
+        threading: DecoderThreading,
     ) -> Result<Self, anyhow::Error> {
         let (control_sender, control_receiver) = smol::channel::unbounded();
 
-        let (packet_sender, packet_receiver) = smol::channel::bounded(128);
+        let packet_queue_capacity = super::packet_queue_capacity(f64::from(stream.avg_frame_rate()));
+        let (packet_sender, packet_receiver) = smol::channel::bounded(packet_queue_capacity);
+        let packet_drain = packet_receiver.clone();
 
-        let decoder_context = ffmpeg_next::codec::Context::from_parameters(stream.parameters())?;
+        let mut decoder_context = ffmpeg_next::codec::Context::from_parameters(stream.parameters())?;
+        decoder_context.set_threading(threading.config());
         let mut packet_decoder = decoder_context.decoder().video()?;
 
-        let clock = StreamClock::new(stream);
+        let clock = StreamClock::new(stream, playback_clock);
+        let flushing = Arc::new(AtomicBool::new(false));
 
         let receiver_thread =
-            std::thread::Builder::new().name("video playback thread".into()).spawn(move || {
-                smol::block_on(async move {
-                    let packet_receiver_impl = async {
-                        loop {
-                            let Ok(packet) = packet_receiver.recv().await else { break };
+            std::thread::Builder::new().name("video playback thread".into()).spawn({
+                let flushing = flushing.clone();
+                move || {
+                    smol::block_on(async move {
+                        let packet_receiver_impl = async {
+                            // Set after a seek: frames before this position are decoded but not
+                            // presented.
+                            let mut seek_position: Option<f64> = None;
 
-                            smol::future::yield_now().await;
+                            loop {
+                                let Ok(message) = packet_receiver.recv().await else { break };
 
-                            packet_decoder.send_packet(&packet).unwrap();
+                                let packet = match message {
+                                    PacketMessage::Packet(packet) => packet,
+                                    PacketMessage::Flush(position) => {
+                                        packet_decoder.flush();
+                                        frame_queue.clear();
+                                        seek_position = Some(position);
+                                        flushing.store(false, Ordering::SeqCst);
+                                        continue;
+                                    }
+                                };
 
-                            let mut decoded_frame = ffmpeg_next::util::frame::Video::empty();
+                                smol::future::yield_now().await;
 
-                            while packet_decoder.receive_frame(&mut decoded_frame).is_ok() {
-                                if let Some(delay) =
-                                    clock.convert_pts_to_instant(decoded_frame.pts())
-                                {
-                                    smol::Timer::after(delay).await;
-                                }
+                                let decode_start = std::time::Instant::now();
+                                packet_decoder.send_packet(&packet).unwrap();
+                                let mut decode_time = decode_start.elapsed();
 
-                                video_frame_callback(&decoded_frame);
-                            }
-                        }
-                    }
-                    .fuse()
-                    .shared();
+                                let mut decoded_frame = frame_queue.take_frame();
 
-                    let mut playing = true;
+                                loop {
+                                    let receive_start = std::time::Instant::now();
+                                    if packet_decoder.receive_frame(&mut decoded_frame).is_err() {
+                                        break;
+                                    }
+                                    // Sending the packet counts towards the first frame it yields
+                                    frame_queue.record_decode_time(decode_time + receive_start.elapsed());
+                                    decode_time = std::time::Duration::ZERO;
 
-                    loop {
-                        let packet_receiver: OptionFuture<_> =
-                            if playing { Some(packet_receiver_impl.clone()) } else { None }.into();
+                                    if flushing.load(Ordering::SeqCst) {
+                                        // A seek is pending, everything still in the decoder is stale.
+                                        continue;
+                                    }
 
-                        smol::pin!(packet_receiver);
+                                    if let Some(position) = seek_position {
+                                        match clock.pts_to_seconds(decoded_frame.pts()) {
+                                            Some(frame_position) if frame_position < position => continue,
+                                            _ => seek_position = None,
+                                        }
+                                    }
 
-                        futures::select! {
-                            _ = packet_receiver => {},
-                            received_command = control_receiver.recv().fuse() => {
-                                match received_command {
-                                    Ok(ControlCommand::Pause) => {
-                                        playing = false;
+                                    // Wait until the frame is due. Frames that are already too late
+                                    // are dropped, the renderer keeps showing the previous one.
+                                    while let Some(delay) = clock.frame_delay(decoded_frame.pts()) {
+                                        if flushing.load(Ordering::SeqCst) {
+                                            break;
+                                        }
+                                        if clock.is_paused() {
+                                            // Keep the frame until playback resumes
+                                            smol::Timer::after(std::time::Duration::from_secs_f64(
+                                                MAX_FRAME_WAIT,
+                                            ))
+                                            .await;
+                                            continue;
+                                        }
+                                        if delay <= 0. {
+                                            break;
+                                        }
+                                        smol::Timer::after(std::time::Duration::from_secs_f64(
+                                            delay.min(MAX_FRAME_WAIT),
+                                        ))
+                                        .await;
                                     }
-                                    Ok(ControlCommand::Play) => {
-                                        playing = true;
+
+                                    if clock
+                                        .frame_delay(decoded_frame.pts())
+                                        .is_some_and(|delay| delay < -MAX_FRAME_LATENESS)
+                                    {
+                                        frame_queue.record_dropped();
+                                        continue;
+                                    }
+
+                                    if flushing.load(Ordering::SeqCst) {
+                                        continue;
                                     }
-                                    Err(_) => {
-                                        // Channel closed -> quit
-                                        return;
+
+                                    // Hand the frame over as is, it only references the
+                                    // decoder's buffers, and continue with a fresh one.
+                                    let position = clock.pts_to_seconds(decoded_frame.pts());
+                                    let presented_frame =
+                                        std::mem::replace(&mut decoded_frame, frame_queue.take_frame());
+                                    frame_queue.push(presented_frame, position);
+                                }
+                                frame_queue.recycle(decoded_frame);
+                            }
+                        }
+                        .fuse()
+                        .shared();
+
+                        let mut playing = true;
+
+                        loop {
+                            let packet_receiver: OptionFuture<_> =
+                                if playing { Some(packet_receiver_impl.clone()) } else { None }.into();
+
+                            smol::pin!(packet_receiver);
+
+                            futures::select! {
+                                _ = packet_receiver => {},
+                                received_command = control_receiver.recv().fuse() => {
+                                    match received_command {
+                                        Ok(PlaybackCommand::Pause) => {
+                                            playing = false;
+                                        }
+                                        Ok(PlaybackCommand::Play) => {
+                                            playing = true;
+                                        }
+                                        Err(_) => {
+                                            // Channel closed -> quit
+                                            return;
+                                        }
                                     }
                                 }
                             }
                         }
-                    }
-                })
+                    })
+                }
             })?;
 
-        Ok(Self { control_sender, packet_sender, receiver_thread: Some(receiver_thread) })
+        Ok(Self {
+            control_sender,
+            packet_sender,
+            packet_drain,
+            flushing,
+            receiver_thread: Some(receiver_thread),
+        })
     }
 
     pub async fn receive_packet(&self, packet: ffmpeg_next::codec::packet::packet::Packet) -> bool {
-        match self.packet_sender.send(packet).await {
+        match self.packet_sender.send(PacketMessage::Packet(packet)).await {
             Ok(_) => return true,
             Err(smol::channel::SendError(_)) => return false,
         }
     }
 
-    pub async fn send_control_message(&self, message: ControlCommand) {
+    /// Discards all queued packets and decoded frames, resuming presentation with the first
+    /// frame at or after `position` (in seconds).
+    pub async fn flush(&self, position: f64) {
+        self.flushing.store(true, Ordering::SeqCst);
+        while self.packet_drain.try_recv().is_ok() {}
+        let _ = self.packet_sender.send(PacketMessage::Flush(position)).await;
+    }
+
+    pub async fn send_control_message(&self, message: PlaybackCommand) {
         self.control_sender.send(message).await.unwrap();
     }
 }
@@ -107,26 +248,33 @@ impl Drop for VideoPlaybackThread {
 
 struct StreamClock {
     time_base_seconds: f64,
-    start_time: std::time::Instant,
+    playback_clock: Arc<PlaybackClock>,
 }
 
 impl StreamClock {
-    fn new(stream: &ffmpeg_next::format::stream::Stream) -> Self {
+    fn new(stream: &ffmpeg_next::format::stream::Stream, playback_clock: Arc<PlaybackClock>) -> Self {
         let time_base_seconds = stream.time_base();
         let time_base_seconds =
             time_base_seconds.numerator() as f64 / time_base_seconds.denominator() as f64;
 
-        let start_time = std::time::Instant::now();
+        Self { time_base_seconds, playback_clock }
+    }
+
+    fn is_paused(&self) -> bool {
+        self.playback_clock.is_paused()
+    }
 
-        Self { time_base_seconds, start_time }
+    fn pts_to_seconds(&self, pts: Option<i64>) -> Option<f64> {
+        pts.map(|pts| pts as f64 * self.time_base_seconds)
     }
 
-    fn convert_pts_to_instant(&self, pts: Option<i64>) -> Option<std::time::Duration> {
-        pts.and_then(|pts| {
-            let pts_since_start =
-                std::time::Duration::from_secs_f64(pts as f64 * self.time_base_seconds);
-            self.start_time.checked_add(pts_since_start)
+    /// Seconds of wall time until the frame with the given pts is due, negative if it is late.
+    ///
+    /// This only depends on the playback clock, which stands still while paused and accounts for
+    /// the playback rate, so presentation continues smoothly after resuming.
+    fn frame_delay(&self, pts: Option<i64>) -> Option<f64> {
+        self.pts_to_seconds(pts).map(|position| {
+            (position - self.playback_clock.position()) / self.playback_clock.rate()
         })
-        .map(|absolute_pts| absolute_pts.duration_since(std::time::Instant::now()))
     }
 }
@@ -6,19 +6,155 @@ in vec2 TexCoord;
 uniform sampler2D yTexture;
 uniform sampler2D uTexture;
 uniform sampler2D vTexture;
+// U and V are interleaved in the red and green channel of uTexture (NV12, P010)
+uniform bool semiPlanar;
+// 0: BT.601, 1: BT.709, 2: BT.2020
+uniform int colorMatrix;
+// Full (0-255) or limited (16-235, chroma 16-240) range
+uniform bool fullRange;
+// Significant bits per sample, samples of more than 8 bits are stored LSB aligned in 16 bits
+uniform int bitDepth;
+// 0: SDR, 1: PQ (HDR10), 2: HLG
+uniform int transfer;
+// Tone mapping of HDR content, 0: Hable, 1: Reinhard, 2: BT.2390
+uniform int toneMapping;
+// Brightest pixel of HDR content in nits
+uniform float peakLuminance;
 
-void main()
+// Luminance of SDR white in nits (ITU-R BT.2408)
+const float SDR_WHITE = 203.0;
+
+// SMPTE ST 2084 constants
+const float PQ_M1 = 0.1593017578125;
+const float PQ_M2 = 78.84375;
+const float PQ_C1 = 0.8359375;
+const float PQ_C2 = 18.8515625;
+const float PQ_C3 = 18.6875;
+
+// PQ signal to linear light in nits
+vec3 pqToLinear(vec3 signal)
+{
+    vec3 p = pow(max(signal, 0.0), vec3(1.0 / PQ_M2));
+    return pow(max(p - PQ_C1, 0.0) / (PQ_C2 - PQ_C3 * p), vec3(1.0 / PQ_M1)) * 10000.0;
+}
+
+// Linear light in nits to PQ signal
+float linearToPq(float nits)
+{
+    float p = pow(max(nits, 0.0) / 10000.0, PQ_M1);
+    return pow((PQ_C1 + PQ_C2 * p) / (1.0 + PQ_C3 * p), PQ_M2);
+}
+
+// HLG signal to display light in nits, for a 1000 nits display
+vec3 hlgToLinear(vec3 signal)
+{
+    const float a = 0.17883277;
+    const float b = 0.28466892;
+    const float c = 0.55991073;
+    vec3 low = signal * signal / 3.0;
+    vec3 high = (exp((signal - c) / a) + b) / 12.0;
+    vec3 scene = mix(low, high, step(0.5, signal));
+
+    // OOTF with the system gamma of a 1000 nits display
+    float luminance = dot(scene, vec3(0.2627, 0.6780, 0.0593));
+    return 1000.0 * pow(max(luminance, 1e-6), 0.2) * scene;
+}
+
+vec3 hable(vec3 x)
+{
+    const float A = 0.15, B = 0.50, C = 0.10, D = 0.20, E = 0.02, F = 0.30;
+    return ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F;
+}
+
+// Maps linear light in nits to SDR, 1.0 being SDR white
+float toneMap(float nits)
 {
-    // Read the YUV values (no need to divide since values are already in 0.0-1.0 range for 8-bit YUV420P)
-    float y = texture(yTexture, TexCoord).r;
-    float u = texture(uTexture, TexCoord).r - 0.5;
-    float v = texture(vTexture, TexCoord).r - 0.5;
+    float peak = max(peakLuminance, SDR_WHITE) / SDR_WHITE;
+    float x = nits / SDR_WHITE;
+    if (toneMapping == 0) {
+        return hable(vec3(x)).x / hable(vec3(peak)).x;
+    } else if (toneMapping == 1) {
+        return x * (1.0 + x / (peak * peak)) / (1.0 + x);
+    } else {
+        // BT.2390 EETF, with the source peak mapped to SDR white
+        float sourcePeak = linearToPq(max(peakLuminance, SDR_WHITE));
+        float e1 = linearToPq(nits) / sourcePeak;
+        float maxLum = linearToPq(SDR_WHITE) / sourcePeak;
+        float ks = 1.5 * maxLum - 0.5;
+        float e2 = e1;
+        if (e1 > ks) {
+            float t = (e1 - ks) / (1.0 - ks);
+            float t2 = t * t;
+            float t3 = t2 * t;
+            e2 = (2.0 * t3 - 3.0 * t2 + 1.0) * ks + (t3 - 2.0 * t2 + t) * (1.0 - ks) + (-2.0 * t3 + 3.0 * t2) * maxLum;
+        }
+        return pqToLinear(vec3(e2 * sourcePeak)).x / SDR_WHITE;
+    }
+}
 
-    // YUV to RGB conversion
-    float r = y + 1.402 * v;
-    float g = y - 0.344136 * u - 0.714136 * v;
-    float b = y + 1.772 * u;
+// Linear HDR RGB in nits with BT.2020 primaries to gamma encoded SDR RGB with BT.709 primaries
+vec3 hdrToSdr(vec3 nits)
+{
+    nits = max(nits * mat3(
+        1.6605, -0.5876, -0.0728,
+        -0.1246, 1.1329, -0.0083,
+        -0.0182, -0.1006, 1.1187
+    ), 0.0);
 
-    FragColor = vec4(r, g, b, 1.0);
+    // Tone map the brightest channel and scale the others along, to keep the hue
+    float signal = max(max(nits.r, nits.g), nits.b);
+    vec3 sdr = signal > 0.0 ? nits * (toneMap(signal) / signal) : vec3(0.0);
+
+    // BT.1886 display gamma
+    return pow(clamp(sdr, 0.0, 1.0), vec3(1.0 / 2.4));
 }
 
+void main()
+{
+    vec3 yuv = vec3(
+        texture(yTexture, TexCoord).r,
+        semiPlanar
+            ? texture(uTexture, TexCoord).rg
+            : vec2(texture(uTexture, TexCoord).r, texture(vTexture, TexCoord).r)
+    );
+
+    // The textures normalize by the container size, rescale to the range of bitDepth bits
+    float maxSample = exp2(float(bitDepth)) - 1.0;
+    float containerMax = bitDepth > 8 ? 65535.0 : 255.0;
+    yuv *= containerMax / maxSample;
+
+    // One code value of an 8-bit signal, scaled to bitDepth bits
+    float unit = exp2(float(bitDepth) - 8.0) / maxSample;
+    float y;
+    vec2 uv;
+    if (fullRange) {
+        y = yuv.x;
+        uv = yuv.yz - 128.0 * unit;
+    } else {
+        y = (yuv.x - 16.0 * unit) / (219.0 * unit);
+        uv = (yuv.yz - 128.0 * unit) / (224.0 * unit);
+    }
+
+    vec2 kRB;
+    if (colorMatrix == 1) {
+        kRB = vec2(0.2126, 0.0722);
+    } else if (colorMatrix == 2) {
+        kRB = vec2(0.2627, 0.0593);
+    } else {
+        kRB = vec2(0.299, 0.114);
+    }
+    float kG = 1.0 - kRB.x - kRB.y;
+
+    float r = y + 2.0 * (1.0 - kRB.x) * uv.y;
+    float b = y + 2.0 * (1.0 - kRB.y) * uv.x;
+    float g = (y - kRB.x * r - kRB.y * b) / kG;
+
+    vec3 rgb = vec3(r, g, b);
+    if (transfer == 1) {
+        rgb = hdrToSdr(pqToLinear(rgb));
+    } else if (transfer == 2) {
+        rgb = hdrToSdr(hlgToLinear(rgb));
+    }
+
+    FragColor = vec4(clamp(rgb, 0.0, 1.0), 1.0);
+}